@@ -0,0 +1,408 @@
+
+use opencv::prelude::*;
+use opencv::core::*;
+use opencv::imgproc::*;
+use glm::*;
+use std::f32::consts;
+use super::Resolution;
+use super::surfaces::{self, SurfaceType};
+
+/// Shape of the ramp across an overlap
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendCurve {
+    Linear,
+    /// smooth S-curve, flat at both ends of the overlap
+    Cosine,
+    /// linear in light output for a projector with this gamma, i.e. the weight raised to
+    /// 1/gamma so the two projectors' light sums to a constant
+    Gamma(f32),
+}
+
+impl BlendCurve {
+    /// "linear", "cosine", "gamma" (2.2) or "gamma:<value>"
+    pub fn parse(input: &str) -> Result<BlendCurve, &'static str> {
+        match input {
+            "linear" => Ok(BlendCurve::Linear),
+            "cosine" => Ok(BlendCurve::Cosine),
+            "gamma" => Ok(BlendCurve::Gamma(2.2)),
+            _ if input.starts_with("gamma:") => input["gamma:".len()..].parse::<f32>()
+                .map(BlendCurve::Gamma)
+                .map_err(|_| "invalid blend gamma"),
+            _ => Err("blend curve should be linear, cosine, gamma or gamma:<value>")
+        }
+    }
+
+    /// Shape a projector's share of the overlap (0 to 1) into its ramp, as light
+    fn ramp(&self, share: f32) -> f32 {
+        let share = share.max(0.).min(1.);
+        match self {
+            BlendCurve::Cosine => 0.5 - 0.5 * (consts::PI * share).cos(),
+            BlendCurve::Linear | BlendCurve::Gamma(_) => share,
+        }
+    }
+
+    /// Turn a share of the light (0 to 1) into a pixel value
+    fn encode(&self, weight: f32) -> f32 {
+        match self {
+            BlendCurve::Gamma(gamma) => weight.powf(1. / gamma),
+            BlendCurve::Linear | BlendCurve::Cosine => weight,
+        }
+    }
+}
+
+/// Where one projector's image lands on the surface, as a mesh over its normalized output
+/// coordinates (origin top-left). The calibrated chessboard corners are inside the frame, so
+/// the mesh is extrapolated by a cell on every side to reach the frame edges.
+pub struct Coverage {
+    /// surface chart positions, `(cols + 2) x (rows + 2)` row-major
+    mesh: Vec<glm::Vec2>,
+    /// number of chessboard corners across and down
+    cols: usize,
+    rows: usize,
+    /// chart bounding box of each mesh cell, row-major
+    cell_bounds: Vec<(glm::Vec2, glm::Vec2)>,
+    /// mesh cells overlapping each bucket of a grid over the whole mesh's bounds
+    buckets: Vec<Vec<usize>>,
+    bucket_origin: glm::Vec2,
+    bucket_size: glm::Vec2,
+}
+
+impl Coverage {
+    /// `scene_coords` are the chessboard corners in row-major order, as produced by the
+    /// calibration for a `warp_res` chessboard
+    pub fn new(surface: &SurfaceType, scene_coords: &[glm::Vec3], warp_res: Resolution) -> Coverage {
+        let (cols, rows) = (warp_res.width as usize, warp_res.height as usize);
+        assert!(cols >= 2 && rows >= 2 && scene_coords.len() == cols * rows, "blending needs a complete chessboard of at least 2x2 corners");
+        let corner = |i: usize, j: usize| surfaces::surface_chart(surface, scene_coords[j * cols + i]);
+
+        let (mesh_cols, mesh_rows) = (cols + 2, rows + 2);
+        let mut mesh = vec![vec2(0., 0.); mesh_cols * mesh_rows];
+        for j in 0..rows {
+            for i in 0..cols {
+                mesh[(j + 1) * mesh_cols + i + 1] = corner(i, j);
+            }
+            // extrapolate left and right edges
+            let row = (j + 1) * mesh_cols;
+            mesh[row] = mesh[row + 1] * 2. - mesh[row + 2];
+            mesh[row + mesh_cols - 1] = mesh[row + mesh_cols - 2] * 2. - mesh[row + mesh_cols - 3];
+        }
+        // then top and bottom, including the frame corners
+        for i in 0..mesh_cols {
+            mesh[i] = mesh[mesh_cols + i] * 2. - mesh[2 * mesh_cols + i];
+            let last = (mesh_rows - 1) * mesh_cols;
+            mesh[last + i] = mesh[last - mesh_cols + i] * 2. - mesh[last - 2 * mesh_cols + i];
+        }
+        let mut coverage = Coverage {
+            mesh: mesh,
+            cols: cols,
+            rows: rows,
+            cell_bounds: vec![],
+            buckets: vec![],
+            bucket_origin: vec2(0., 0.),
+            bucket_size: vec2(1., 1.),
+        };
+        coverage.build_index();
+        coverage
+    }
+
+    /// Bucket the mesh cells into a grid with as many buckets as cells, so `locate` only has
+    /// to look at the few cells near a point
+    fn build_index(&mut self) {
+        let (cells_x, cells_y) = self.cells();
+        for j in 0..cells_y {
+            for i in 0..cells_x {
+                let p = self.cell(i, j);
+                let min = vec2(p[0].x.min(p[1].x).min(p[2].x).min(p[3].x), p[0].y.min(p[1].y).min(p[2].y).min(p[3].y));
+                let max = vec2(p[0].x.max(p[1].x).max(p[2].x).max(p[3].x), p[0].y.max(p[1].y).max(p[2].y).max(p[3].y));
+                self.cell_bounds.push((min, max));
+            }
+        }
+
+        let (min, max) = self.bounds();
+        self.bucket_origin = min;
+        self.bucket_size = vec2(((max.x - min.x) / cells_x as f32).max(1e-6), ((max.y - min.y) / cells_y as f32).max(1e-6));
+        self.buckets = vec![vec![]; cells_x * cells_y];
+        for (cell, &(min, max)) in self.cell_bounds.iter().enumerate() {
+            let (x0, y0) = self.bucket(min);
+            let (x1, y1) = self.bucket(max);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    self.buckets[y * cells_x + x].push(cell);
+                }
+            }
+        }
+    }
+
+    /// Grid bucket holding a chart position, clamped to the grid
+    fn bucket(&self, point: glm::Vec2) -> (usize, usize) {
+        let (cells_x, cells_y) = self.cells();
+        let x = ((point.x - self.bucket_origin.x) / self.bucket_size.x).max(0.) as usize;
+        let y = ((point.y - self.bucket_origin.y) / self.bucket_size.y).max(0.) as usize;
+        (x.min(cells_x - 1), y.min(cells_y - 1))
+    }
+
+    /// Smallest rectangle of the surface chart holding the whole projector frame, as the
+    /// minimum and maximum corners
+    pub fn bounds(&self) -> (glm::Vec2, glm::Vec2) {
+        let mut min = vec2(std::f32::MAX, std::f32::MAX);
+        let mut max = vec2(std::f32::MIN, std::f32::MIN);
+        for v in self.mesh.iter() {
+            min = vec2(min.x.min(v.x), min.y.min(v.y));
+            max = vec2(max.x.max(v.x), max.y.max(v.y));
+        }
+        (min, max)
+    }
+
+    fn cells(&self) -> (usize, usize) {
+        (self.cols + 1, self.rows + 1)
+    }
+
+    /// Normalized output position of a mesh vertex
+    fn vertex_uv(&self, i: usize, j: usize) -> glm::Vec2 {
+        let (cells_x, cells_y) = self.cells();
+        vec2(i as f32 / cells_x as f32, j as f32 / cells_y as f32)
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> glm::Vec2 {
+        self.mesh[j * (self.cols + 2) + i]
+    }
+
+    /// Chart positions of a mesh cell's corners, clockwise from the top-left
+    fn cell(&self, i: usize, j: usize) -> [glm::Vec2; 4] {
+        [self.vertex(i, j), self.vertex(i + 1, j), self.vertex(i + 1, j + 1), self.vertex(i, j + 1)]
+    }
+
+    /// Surface chart position lit by a normalized output position
+    pub fn chart_at(&self, uv: glm::Vec2) -> glm::Vec2 {
+        let (cells_x, cells_y) = self.cells();
+        let x = (uv.x * cells_x as f32).max(0.).min(cells_x as f32);
+        let y = (uv.y * cells_y as f32).max(0.).min(cells_y as f32);
+        let (i, j) = ((x as usize).min(cells_x - 1), (y as usize).min(cells_y - 1));
+        let (fx, fy) = (x - i as f32, y - j as f32);
+        let top = self.vertex(i, j) * (1. - fx) + self.vertex(i + 1, j) * fx;
+        let bottom = self.vertex(i, j + 1) * (1. - fx) + self.vertex(i + 1, j + 1) * fx;
+        top * (1. - fy) + bottom * fy
+    }
+
+    /// Normalized output position that lights a surface chart position, if this projector
+    /// reaches it. The inverse of `chart_at`.
+    pub fn locate(&self, point: glm::Vec2) -> Option<glm::Vec2> {
+        let (cells_x, cells_y) = self.cells();
+        let (x, y) = self.bucket(point);
+        for &cell in self.buckets[y * cells_x + x].iter() {
+            let (min, max) = self.cell_bounds[cell];
+            if point.x < min.x || point.x > max.x || point.y < min.y || point.y > max.y {
+                continue;
+            }
+            let (i, j) = (cell % cells_x, cell / cells_x);
+            if let Some((fx, fy)) = unbilinear(self.cell(i, j), point) {
+                return Some(vec2((i as f32 + fx) / cells_x as f32, (j as f32 + fy) / cells_y as f32));
+            }
+        }
+        None
+    }
+}
+
+/// Position within a cell, as fractions across and down, that bilinear interpolation of its
+/// corners (clockwise from the top-left) maps to `p`, if `p` is inside the cell
+fn unbilinear(corners: [glm::Vec2; 4], p: glm::Vec2) -> Option<(f32, f32)> {
+    // in double precision, so the steps can get much smaller than the chart coordinates
+    let [a, b, c, d] = corners;
+    let lerp = |from: glm::Vec2, to: glm::Vec2, f: f64| (from.x as f64 + (to.x - from.x) as f64 * f, from.y as f64 + (to.y - from.y) as f64 * f);
+    let (mut s, mut t) = (0.5_f64, 0.5_f64);
+    // Newton's method, from the middle of the cell
+    for _ in 0..20 {
+        let (top, bottom) = (lerp(a, b, s), lerp(d, c, s));
+        let (left, right) = (lerp(a, d, t), lerp(b, c, t));
+        let error = (top.0 + (bottom.0 - top.0) * t - p.x as f64, top.1 + (bottom.1 - top.1) * t - p.y as f64);
+        let ds = (right.0 - left.0, right.1 - left.1);
+        let dt = (bottom.0 - top.0, bottom.1 - top.1);
+        let det = ds.0 * dt.1 - ds.1 * dt.0;
+        if det.abs() < 1e-18 {
+            return None;
+        }
+        let step_s = (error.0 * dt.1 - error.1 * dt.0) / det;
+        let step_t = (ds.0 * error.1 - ds.1 * error.0) / det;
+        s -= step_s;
+        t -= step_t;
+        if step_s.abs() < 1e-9 && step_t.abs() < 1e-9 {
+            let eps = 1e-5;
+            let inside = s >= -eps && s <= 1. + eps && t >= -eps && t <= 1. + eps;
+            return if inside { Some((s.max(0.).min(1.) as f32, t.max(0.).min(1.) as f32)) } else { None };
+        }
+    }
+    None
+}
+
+/// Weights of `a`, `b` and `c` that give `p`, if `p` is inside the triangle
+pub fn barycentric(p: glm::Vec2, a: glm::Vec2, b: glm::Vec2, c: glm::Vec2) -> Option<(f32, f32, f32)> {
+    let det = (b.y - c.y) * (a.x - c.x) + (c.x - b.x) * (a.y - c.y);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let wa = ((b.y - c.y) * (p.x - c.x) + (c.x - b.x) * (p.y - c.y)) / det;
+    let wb = ((c.y - a.y) * (p.x - c.x) + (a.x - c.x) * (p.y - c.y)) / det;
+    let wc = 1. - wa - wb;
+    let eps = -1e-5;
+    if wa >= eps && wb >= eps && wc >= eps { Some((wa, wb, wc)) } else { None }
+}
+
+/// Distance from a normalized output position to the nearest edge of the frame
+fn edge_distance(uv: glm::Vec2) -> f32 {
+    uv.x.min(1. - uv.x).min(uv.y).min(1. - uv.y).max(0.)
+}
+
+/// Pixel value (0 to 1) for projector `index` at a normalized output position. Each projector
+/// lighting the same spot gets a share proportional to how far the spot is from its frame edge,
+/// so the shares fall smoothly to zero at every edge that lies in an overlap. The shares are
+/// shaped by the curve and normalised over all the projectors there, so their light sums to one
+/// however many overlap.
+pub fn blend_weight(coverages: &[Coverage], index: usize, uv: glm::Vec2, curve: BlendCurve) -> f32 {
+    let own = edge_distance(uv);
+    let point = coverages[index].chart_at(uv);
+    let others: Vec<f32> = coverages.iter().enumerate()
+        .filter(|&(i, _)| i != index)
+        .filter_map(|(_, other)| other.locate(point))
+        .map(edge_distance)
+        .collect();
+    let total = own + others.iter().sum::<f32>();
+    if total <= 0. {
+        return 1.;
+    }
+    let ramp = curve.ramp(own / total);
+    let ramps = ramp + others.iter().map(|&d| curve.ramp(d / total)).sum::<f32>();
+    if ramps <= 0. {
+        return 1.;
+    }
+    curve.encode(ramp / ramps)
+}
+
+/// Blend weight at each chessboard corner, in the same order as the warp
+pub fn vertex_alphas(coverages: &[Coverage], index: usize, curve: BlendCurve) -> Vec<f32> {
+    let coverage = &coverages[index];
+    let mut alphas = vec![];
+    for j in 0..coverage.rows {
+        for i in 0..coverage.cols {
+            alphas.push(blend_weight(coverages, index, coverage.vertex_uv(i + 1, j + 1), curve));
+        }
+    }
+    alphas
+}
+
+/// Sample maps this many pixels apart and interpolate in between
+const MASK_STEP: i32 = 4;
+
+/// Render an 8 bit image at the projector's output resolution from a function of the
+/// normalized output position returning values from 0 to 1. `channels` is 1 for grayscale
+/// (the first value is used) or 3 for BGR.
+pub fn render_map<F: Fn(glm::Vec2) -> [f32; 3]>(projector_res: Resolution, channels: i32, value_at: F) -> opencv::Result<Mat> {
+    let (w, h) = (projector_res.width, projector_res.height);
+    let (sw, sh) = ((w + MASK_STEP - 1) / MASK_STEP + 1, (h + MASK_STEP - 1) / MASK_STEP + 1);
+    let (float_type, byte_type) = if channels == 1 { (CV_32FC1, CV_8UC1) } else { (CV_32FC3, CV_8UC3) };
+    let mut samples = Mat::new_rows_cols_with_default(sh, sw, float_type, Scalar::all(0.))?;
+    for y in 0..sh {
+        for x in 0..sw {
+            let uv = vec2(x as f32 / (sw - 1) as f32, y as f32 / (sh - 1) as f32);
+            let value = value_at(uv);
+            if channels == 1 {
+                *samples.at_2d_mut::<f32>(y, x)? = value[0] * 255.;
+            } else {
+                *samples.at_2d_mut::<Vec3f>(y, x)? = Vec3f::from([value[0] * 255., value[1] * 255., value[2] * 255.]);
+            }
+        }
+    }
+    let mut resized = Mat::default()?;
+    resize(&samples, &mut resized, Size::new(w, h), 0., 0., INTER_LINEAR)?;
+    let mut image = Mat::default()?;
+    resized.convert_to(&mut image, byte_type, 1., 0.)?;
+    Ok(image)
+}
+
+/// Grayscale blend mask at the projector's output resolution, to multiply the output by
+pub fn blend_mask(coverages: &[Coverage], index: usize, projector_res: Resolution, curve: BlendCurve) -> opencv::Result<Mat> {
+    render_map(projector_res, 1, |uv| {
+        let weight = blend_weight(coverages, index, uv, curve);
+        [weight, weight, weight]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Coverage of a projector lighting the rectangle of the wall from `min` to `max`
+    fn rectangle(min: glm::Vec2, max: glm::Vec2, warp_res: Resolution) -> Coverage {
+        let (cols, rows) = (warp_res.width, warp_res.height);
+        let mut scene_coords = vec![];
+        for j in 0..rows {
+            for i in 0..cols {
+                let u = (i + 1) as f32 / (cols + 1) as f32;
+                let v = (j + 1) as f32 / (rows + 1) as f32;
+                scene_coords.push(vec3(min.x + (max.x - min.x) * u, min.y + (max.y - min.y) * v, 0.));
+            }
+        }
+        Coverage::new(&SurfaceType::Wall, &scene_coords, warp_res)
+    }
+
+    /// Share of the light a pixel value gives
+    fn light(curve: BlendCurve, value: f32) -> f32 {
+        match curve {
+            BlendCurve::Gamma(gamma) => value.powf(gamma),
+            _ => value,
+        }
+    }
+
+    #[test]
+    fn parses_blend_curves() {
+        assert_eq!(BlendCurve::parse("linear"), Ok(BlendCurve::Linear));
+        assert_eq!(BlendCurve::parse("cosine"), Ok(BlendCurve::Cosine));
+        assert_eq!(BlendCurve::parse("gamma"), Ok(BlendCurve::Gamma(2.2)));
+        assert_eq!(BlendCurve::parse("gamma:1.8"), Ok(BlendCurve::Gamma(1.8)));
+        assert!(BlendCurve::parse("gamma:steep").is_err());
+        assert!(BlendCurve::parse("smooth").is_err());
+    }
+
+    #[test]
+    fn locate_inverts_chart_at() {
+        // a keystoned, bent projection
+        let (cols, rows) = (7, 5);
+        let mut scene_coords = vec![];
+        for j in 0..rows {
+            for i in 0..cols {
+                let (x, y) = (i as f32, j as f32);
+                scene_coords.push(vec3(x * (1. + 0.05 * y) + 0.02 * y * y, y + 0.03 * x * x, 0.));
+            }
+        }
+        let coverage = Coverage::new(&SurfaceType::Wall, &scene_coords, Resolution { width: cols, height: rows });
+        for &(u, v) in [(0.01, 0.02), (0.5, 0.5), (0.3, 0.77), (0.99, 0.6), (0.125, 0.98)].iter() {
+            let uv = coverage.locate(coverage.chart_at(vec2(u, v))).expect("point should be covered");
+            assert!((uv.x - u).abs() < 1e-4 && (uv.y - v).abs() < 1e-4, "{},{} located at {},{}", u, v, uv.x, uv.y);
+        }
+        assert_eq!(coverage.locate(vec2(-5., 2.)), None);
+        assert_eq!(coverage.locate(vec2(3., 50.)), None);
+    }
+
+    #[test]
+    fn overlapping_weights_sum_to_one() {
+        let warp_res = Resolution { width: 9, height: 6 };
+        // two projectors overlap from x = 1, all three from 1.5 to 2
+        let coverages = vec![
+            rectangle(vec2(0., 0.), vec2(2., 1.), warp_res),
+            rectangle(vec2(1., 0.), vec2(3., 1.), warp_res),
+            rectangle(vec2(1.5, 0.), vec2(3.5, 1.), warp_res),
+        ];
+        for &curve in [BlendCurve::Linear, BlendCurve::Cosine, BlendCurve::Gamma(2.2)].iter() {
+            for step in 1..70 {
+                let point = vec2(step as f32 * 0.05, 0.3 + step as f32 * 0.005);
+                let lit: Vec<(usize, glm::Vec2)> = coverages.iter().enumerate()
+                    .filter_map(|(i, coverage)| coverage.locate(point).map(|uv| (i, uv)))
+                    .collect();
+                let total: f32 = lit.iter().map(|&(i, uv)| light(curve, blend_weight(&coverages, i, uv, curve))).sum();
+                assert!((total - 1.).abs() < 1e-3, "{:?} light at {},{} from {} projectors sums to {}", curve, point.x, point.y, lit.len(), total);
+                if lit.len() == 1 {
+                    assert!((blend_weight(&coverages, lit[0].0, lit[0].1, curve) - 1.).abs() < 1e-6);
+                }
+            }
+        }
+    }
+}
@@ -1,16 +1,91 @@
 
 use opencv::prelude::*;
 use opencv::core::*;
+use opencv::types::*;
+use opencv::imgcodecs;
+use opencv::imgproc::*;
+use opencv::calib3d::*;
 use xmltree::Element;
-use std::fs::File;
-use log::{info};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use log::{info, debug};
+use super::camera_response::{self, CameraResponse};
+
+/// Lens distortion model the coefficients belong to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistortionModel {
+    /// OpenCV's standard model (k1, k2, p1, p2[, k3[, k4, k5, k6]])
+    RadialTangential,
+    /// OpenCV's fisheye (equidistant) model (k1, k2, k3, k4)
+    Fisheye,
+}
 
 pub struct Calibration {
     pub camera_matrix: Matx33d,
     pub distortion_coefficients: Mat,
+    pub distortion_model: DistortionModel,
     pub fov: f32,
     pub image_width: i32,
     pub image_height: i32,
+    /// inverse response curve, if one has been recovered for this camera
+    pub response: Option<CameraResponse>,
+}
+
+impl Calibration {
+    /// Build a calibration from an OpenCV style camera matrix (principal point relative to the
+    /// center of the top-left pixel). The vertical field of view is derived from fy.
+    pub fn new(camera_matrix: Matx33d, distortion_coefficients: &[f64], distortion_model: DistortionModel, image_width: i32, image_height: i32) -> Calibration {
+        // get the camera FOV from the intrinsic camera matrix
+        let fy: f32 = camera_matrix.get((1, 1)).unwrap().clone() as f32;
+        let _aa = (image_height as f32).atan2(2. * fy);
+        let fov = (2. * _aa).to_degrees();
+
+        info!("physical camera field of view calculated as {} degrees", fov);
+
+        Calibration {
+            camera_matrix: camera_matrix,
+            distortion_coefficients: Mat::from_slice(distortion_coefficients).unwrap(),
+            distortion_model: distortion_model,
+            fov: fov,
+            image_width: image_width,
+            image_height: image_height,
+            response: None
+        }
+    }
+}
+
+/// Load a camera calibration, picking the importer from the file name. An optional
+/// `#<camera>` suffix selects a camera from files holding several (COLMAP camera ID or
+/// Kalibr camchain key, e.g. `camchain.yaml#cam1`).
+///
+/// * `.xml` - OpenCV calibration XML
+/// * `cameras.txt` / `.txt` - COLMAP cameras
+/// * `.yaml` / `.yml` - Kalibr camchain
+/// * `.json` / `.csv` - MATLAB Camera Calibrator export
+pub fn load_calibration(fname: &str) -> Result<Calibration, String> {
+    let (path, camera) = match fname.rfind('#') {
+        Some(i) => (&fname[..i], Some(&fname[i + 1..])),
+        None => (fname, None)
+    };
+    let lower = path.to_ascii_lowercase();
+    let calibration = if lower.ends_with(".xml") {
+        load_calibration_file(path).ok_or_else(|| format!("failed to load OpenCV calibration XML {}", path))
+    } else if lower.ends_with(".txt") {
+        load_colmap_cameras(path, camera)
+    } else if lower.ends_with(".yaml") || lower.ends_with(".yml") {
+        load_kalibr_camchain(path, camera)
+    } else if lower.ends_with(".json") {
+        load_matlab_json(path)
+    } else if lower.ends_with(".csv") {
+        load_matlab_csv(path)
+    } else {
+        Err(format!("don't know how to load camera calibration from {}", path))
+    }?;
+    let calibration = Calibration { response: camera_response::load_for_calibration(fname)?, ..calibration };
+
+    info!("camera matrix and distortion coefficients loaded from {}", &fname);
+    Ok(calibration)
 }
 
 pub fn load_calibration_file(fname: &str) -> Option<Calibration> {
@@ -28,8 +103,7 @@ pub fn load_calibration_file(fname: &str) -> Option<Calibration> {
     let node = root_elm.get_mut_child("distortion_coefficients").expect("can't find distortion_coefficients element");
     let data = node.get_mut_child("data").expect("can't find distortion_coefficients data");
     let values = data.get_text().unwrap();
-    let floats: Vec<f64> = values.split_ascii_whitespace().map(|word| word.parse().unwrap()).collect();
-    let distortion_coefficients = Mat::from_slice(&floats).unwrap();
+    let distortion_coefficients: Vec<f64> = values.split_ascii_whitespace().map(|word| word.parse().unwrap()).collect();
 
     let node = root_elm.get_mut_child("image_height").expect("can't find image_height element");
     let image_height: i32 = node.get_text().as_deref().unwrap().parse().unwrap();
@@ -37,13 +111,501 @@ pub fn load_calibration_file(fname: &str) -> Option<Calibration> {
     let node = root_elm.get_mut_child("image_width").expect("can't find image_width element");
     let image_width: i32 = node.get_text().as_deref().unwrap().parse().unwrap();
 
-    // get the camera FOV from the intrinsic camera matrix
-    let fy: f32 = camera_matrix.get((1, 1)).unwrap().clone() as f32;
-    let _aa = (image_height as f32).atan2(2. * fy);
-    let fov = (2. * _aa).to_degrees();
+    // files written by cv::fisheye calibration carry a distortion_model element
+    let distortion_model = match root_elm.get_child("distortion_model").and_then(|n| n.get_text()) {
+        Some(model) if model.trim() == "fisheye" => DistortionModel::Fisheye,
+        _ => DistortionModel::RadialTangential
+    };
 
-    info!("camera matrix and distortion coefficients loaded from {}", &fname);
-    info!("physical camera field of view calculated as {} degrees", fov);
+    Some(Calibration::new(camera_matrix, &distortion_coefficients, distortion_model, image_width, image_height))
+}
+
+fn pinhole_matrix(fx: f64, fy: f64, cx: f64, cy: f64, skew: f64) -> Matx33d {
+    Matx33d::from([
+        fx, skew, cx,
+        0., fy, cy,
+        0., 0., 1.
+    ])
+}
+
+/// Import a camera from a COLMAP `cameras.txt` file. Uses the first camera unless a camera ID
+/// is given.
+///
+/// COLMAP puts the image origin at the top-left corner of the top-left pixel, whereas OpenCV
+/// uses the center of that pixel, so the principal point is shifted by half a pixel.
+pub fn load_colmap_cameras(fname: &str, camera_id: Option<&str>) -> Result<Calibration, String> {
+    let text = fs::read_to_string(fname).map_err(|e| format!("failed to read {}: {}", fname, e))?;
+
+    for line in text.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // CAMERA_ID MODEL WIDTH HEIGHT PARAMS[]
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        if words.len() < 4 {
+            return Err(format!("malformed COLMAP camera line: {}", line));
+        }
+        if let Some(id) = camera_id {
+            if words[0] != id { continue; }
+        }
+
+        let model = words[1];
+        let width: i32 = words[2].parse().map_err(|_| format!("bad camera width in {}", line))?;
+        let height: i32 = words[3].parse().map_err(|_| format!("bad camera height in {}", line))?;
+        let p: Vec<f64> = words[4..].iter()
+            .map(|w| w.parse().map_err(|_| format!("bad camera parameter {} in {}", w, line)))
+            .collect::<Result<_, _>>()?;
+
+        let expected = match model {
+            "SIMPLE_PINHOLE" => 3,
+            "PINHOLE" | "SIMPLE_RADIAL" | "SIMPLE_RADIAL_FISHEYE" => 4,
+            "RADIAL" | "RADIAL_FISHEYE" => 5,
+            "OPENCV" | "OPENCV_FISHEYE" => 8,
+            "FULL_OPENCV" => 12,
+            _ => return Err(format!("unsupported COLMAP camera model {}", model))
+        };
+        if p.len() != expected {
+            return Err(format!("COLMAP {} camera needs {} parameters, found {}", model, expected, p.len()));
+        }
+
+        // (fx, fy, cx, cy, distortion, model)
+        let (fx, fy, cx, cy, dist, dist_model) = match model {
+            "SIMPLE_PINHOLE" => (p[0], p[0], p[1], p[2], vec![0.; 4], DistortionModel::RadialTangential),
+            "PINHOLE" => (p[0], p[1], p[2], p[3], vec![0.; 4], DistortionModel::RadialTangential),
+            "SIMPLE_RADIAL" => (p[0], p[0], p[1], p[2], vec![p[3], 0., 0., 0.], DistortionModel::RadialTangential),
+            "RADIAL" => (p[0], p[0], p[1], p[2], vec![p[3], p[4], 0., 0.], DistortionModel::RadialTangential),
+            "OPENCV" => (p[0], p[1], p[2], p[3], p[4..8].to_vec(), DistortionModel::RadialTangential),
+            "FULL_OPENCV" => (p[0], p[1], p[2], p[3], p[4..12].to_vec(), DistortionModel::RadialTangential),
+            "SIMPLE_RADIAL_FISHEYE" => (p[0], p[0], p[1], p[2], vec![p[3], 0., 0., 0.], DistortionModel::Fisheye),
+            "RADIAL_FISHEYE" => (p[0], p[0], p[1], p[2], vec![p[3], p[4], 0., 0.], DistortionModel::Fisheye),
+            _ => (p[0], p[1], p[2], p[3], p[4..8].to_vec(), DistortionModel::Fisheye), // OPENCV_FISHEYE
+        };
+
+        info!("using COLMAP camera {} ({})", words[0], model);
+        let camera_matrix = pinhole_matrix(fx, fy, cx - 0.5, cy - 0.5, 0.);
+        return Ok(Calibration::new(camera_matrix, &dist, dist_model, width, height));
+    }
+
+    match camera_id {
+        Some(id) => Err(format!("camera {} not found in {}", id, fname)),
+        None => Err(format!("no cameras found in {}", fname))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct KalibrCamera {
+    camera_model: String,
+    intrinsics: Vec<f64>,
+    distortion_model: String,
+    distortion_coeffs: Vec<f64>,
+    resolution: Vec<i32>,
+}
+
+/// Import a camera from a Kalibr camchain YAML file. Uses `cam0` unless another key is given.
+/// Kalibr shares OpenCV's pixel-center convention so the intrinsics are used as-is.
+pub fn load_kalibr_camchain(fname: &str, camera: Option<&str>) -> Result<Calibration, String> {
+    let file = File::open(fname).map_err(|e| format!("failed to open {}: {}", fname, e))?;
+    let chain: BTreeMap<String, KalibrCamera> = serde_yaml::from_reader(file)
+        .map_err(|e| format!("failed to parse Kalibr camchain {}: {}", fname, e))?;
+    let key = camera.unwrap_or("cam0");
+    let cam = chain.get(key).ok_or_else(|| format!("camera {} not found in {}", key, fname))?;
+
+    if cam.camera_model != "pinhole" {
+        return Err(format!("unsupported Kalibr camera model {}", cam.camera_model));
+    }
+    if cam.intrinsics.len() != 4 || cam.resolution.len() != 2 {
+        return Err(String::from("Kalibr pinhole camera needs 4 intrinsics and a 2 value resolution"));
+    }
+    let dist_model = match cam.distortion_model.as_str() {
+        // radtan coefficients are k1, k2, p1, p2, the same order as OpenCV
+        "radtan" if cam.distortion_coeffs.len() == 4 => DistortionModel::RadialTangential,
+        "equidistant" if cam.distortion_coeffs.len() == 4 => DistortionModel::Fisheye,
+        "none" => DistortionModel::RadialTangential,
+        other => return Err(format!("unsupported Kalibr distortion model {} with {} coefficients", other, cam.distortion_coeffs.len()))
+    };
+    let dist = if cam.distortion_coeffs.is_empty() { vec![0.; 4] } else { cam.distortion_coeffs.clone() };
+
+    // intrinsics are fu, fv, pu, pv
+    let i = &cam.intrinsics;
+    info!("using Kalibr camera {} ({}-{})", key, cam.camera_model, cam.distortion_model);
+    Ok(Calibration::new(pinhole_matrix(i[0], i[1], i[2], i[3], 0.), &dist, dist_model, cam.resolution[0], cam.resolution[1]))
+}
+
+/// Camera intrinsics as exported from MATLAB (`jsonencode(cameraParams)` or a CSV of the same
+/// properties). Property names follow MATLAB's cameraIntrinsics/cameraParameters objects.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+struct MatlabIntrinsics {
+    focal_length: Option<Vec<f64>>,
+    principal_point: Option<Vec<f64>>,
+    image_size: Option<Vec<f64>>,
+    radial_distortion: Option<Vec<f64>>,
+    tangential_distortion: Option<Vec<f64>>,
+    skew: Option<f64>,
+    /// transposed camera matrix (older cameraParameters objects)
+    intrinsic_matrix: Option<Vec<Vec<f64>>>,
+    /// camera matrix (R2022b and later)
+    #[serde(rename = "K")]
+    k: Option<Vec<Vec<f64>>>,
+    /// newer cameraParameters nest everything in an Intrinsics property
+    intrinsics: Option<Box<MatlabIntrinsics>>,
+}
+
+/// Import a camera from a MATLAB Camera Calibrator JSON export
+pub fn load_matlab_json(fname: &str) -> Result<Calibration, String> {
+    let json_str = fs::read_to_string(fname).map_err(|e| format!("failed to read {}: {}", fname, e))?;
+    let params: MatlabIntrinsics = serde_json::from_str(&json_str)
+        .map_err(|e| format!("failed to parse MATLAB calibration {}: {}", fname, e))?;
+    matlab_to_calibration(params)
+}
+
+/// Import a camera from a MATLAB CSV export with one property per row, e.g.
+/// `FocalLength,1500.2,1498.7` or `ImageSize,4000,6000`
+pub fn load_matlab_csv(fname: &str) -> Result<Calibration, String> {
+    let text = fs::read_to_string(fname).map_err(|e| format!("failed to read {}: {}", fname, e))?;
+    let mut params = MatlabIntrinsics::default();
+
+    for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        let mut cells = line.split(',').map(|c| c.trim().trim_matches('"'));
+        let name = cells.next().unwrap();
+        let values: Vec<f64> = match cells.map(|c| c.parse::<f64>()).collect() {
+            Ok(values) => values,
+            Err(_) => continue // header row
+        };
+        match name {
+            "FocalLength" => params.focal_length = Some(values),
+            "PrincipalPoint" => params.principal_point = Some(values),
+            "ImageSize" => params.image_size = Some(values),
+            "RadialDistortion" => params.radial_distortion = Some(values),
+            "TangentialDistortion" => params.tangential_distortion = Some(values),
+            "Skew" => params.skew = values.first().cloned(),
+            _ => {}
+        }
+    }
+    matlab_to_calibration(params)
+}
+
+/// MATLAB is 1-based (the center of the top-left pixel is at 1,1) so the principal point is
+/// shifted by a whole pixel. ImageSize is [rows, columns].
+fn matlab_to_calibration(params: MatlabIntrinsics) -> Result<Calibration, String> {
+    let params = match params.intrinsics {
+        Some(nested) => *nested,
+        None => params
+    };
+
+    let (fx, fy, cx, cy, skew) = if let (Some(f), Some(c)) = (&params.focal_length, &params.principal_point) {
+        if f.len() != 2 || c.len() != 2 {
+            return Err(String::from("FocalLength and PrincipalPoint need two values each"));
+        }
+        (f[0], f[1], c[0], c[1], params.skew.unwrap_or(0.))
+    } else if let Some(k) = &params.k {
+        (k[0][0], k[1][1], k[0][2], k[1][2], k[0][1])
+    } else if let Some(m) = &params.intrinsic_matrix {
+        // IntrinsicMatrix is K transposed
+        (m[0][0], m[1][1], m[2][0], m[2][1], m[1][0])
+    } else {
+        return Err(String::from("MATLAB calibration has no FocalLength/PrincipalPoint or camera matrix"));
+    };
+
+    let size = params.image_size.ok_or("MATLAB calibration has no ImageSize")?;
+    if size.len() != 2 {
+        return Err(String::from("ImageSize needs two values"));
+    }
+
+    let radial = params.radial_distortion.unwrap_or_default();
+    let tangential = params.tangential_distortion.unwrap_or_default();
+    if radial.len() > 3 || (tangential.len() != 0 && tangential.len() != 2) {
+        return Err(String::from("MATLAB distortion needs up to 3 radial and 2 tangential coefficients"));
+    }
+    let radial_at = |i: usize| radial.get(i).cloned().unwrap_or(0.);
+    let tangential_at = |i: usize| tangential.get(i).cloned().unwrap_or(0.);
+    let dist = [radial_at(0), radial_at(1), tangential_at(0), tangential_at(1), radial_at(2)];
+
+    let camera_matrix = pinhole_matrix(fx, fy, cx - 1., cy - 1., skew);
+    Ok(Calibration::new(camera_matrix, &dist, DistortionModel::RadialTangential, size[1] as i32, size[0] as i32))
+}
+
+/// Check that the intrinsics are plausible for the image size they claim to describe, and for
+/// the lens's vertical field of view in degrees if it's known. Catches calibration files for a
+/// different lens or camera body before they produce a subtly bad warp.
+pub fn validate(calibration: &Calibration, lens_fov: Option<f32>) -> Result<(), Vec<String>> {
+    let mut problems = vec![];
+    let w = calibration.image_width as f64;
+    let h = calibration.image_height as f64;
+    let k = &calibration.camera_matrix;
+    let (fx, fy) = (*k.get((0, 0)).unwrap(), *k.get((1, 1)).unwrap());
+    let (cx, cy) = (*k.get((0, 2)).unwrap(), *k.get((1, 2)).unwrap());
+
+    if calibration.image_width <= 0 || calibration.image_height <= 0 {
+        problems.push(format!("image size {}x{} is invalid", w, h));
+        return Err(problems);
+    }
+
+    // principal point
+    if cx < 0. || cy < 0. || cx >= w || cy >= h {
+        problems.push(format!("principal point ({:.1}, {:.1}) lies outside the {}x{} image", cx, cy, w, h));
+    }
 
-    Some(Calibration {camera_matrix: camera_matrix, distortion_coefficients: distortion_coefficients, fov: fov, image_width: image_width, image_height: image_height})
+    // focal lengths
+    if fx <= 0. || fy <= 0. {
+        problems.push(format!("focal lengths must be positive (fx = {}, fy = {})", fx, fy));
+        return Err(problems);
+    }
+    if (fx / fy - 1.).abs() > 0.05 {
+        problems.push(format!("fx ({:.1}) and fy ({:.1}) differ by more than 5%, pixels should be close to square", fx, fy));
+    }
+    if let Some(lens_fov) = lens_fov {
+        let fov = focal_length_fov(calibration);
+        if (fov / lens_fov as f64 - 1.).abs() > 0.1 {
+            problems.push(format!("fy ({:.1}) gives a vertical field of view of {:.1} degrees, the lens has {:.1} degrees", fy, fov, lens_fov));
+        }
+    }
+
+    // distortion must map points further from the principal point to points further away,
+    // otherwise the polynomial has folded back on itself inside the frame
+    if !radial_distortion_is_monotonic(calibration) {
+        problems.push(String::from("radial distortion is not monotonic across the frame (distortion coefficients for a different lens?)"));
+    }
+
+    if problems.is_empty() { Ok(()) } else { Err(problems) }
+}
+
+/// Vertical field of view in degrees implied by fy and the image height
+fn focal_length_fov(calibration: &Calibration) -> f64 {
+    let fy = *calibration.camera_matrix.get((1, 1)).unwrap();
+    let h = calibration.image_height as f64;
+    match calibration.distortion_model {
+        DistortionModel::RadialTangential => (2. * (h / (2. * fy)).atan()).to_degrees(),
+        // equidistant, the angle from the optical axis is proportional to the radius
+        DistortionModel::Fisheye => (h / fy).to_degrees(),
+    }
+}
+
+/// Step out from the principal point along the distortion model's radius, evaluating the
+/// forward radial distortion, and check that the distorted radius keeps increasing until it
+/// reaches the furthest corner of the frame
+fn radial_distortion_is_monotonic(calibration: &Calibration) -> bool {
+    let k = &calibration.camera_matrix;
+    let (fx, fy) = (*k.get((0, 0)).unwrap(), *k.get((1, 1)).unwrap());
+    let (cx, cy) = (*k.get((0, 2)).unwrap(), *k.get((1, 2)).unwrap());
+    let (w, h) = ((calibration.image_width - 1) as f64, (calibration.image_height - 1) as f64);
+    // distorted radius of the furthest corner, in normalized coordinates
+    let corner_radius = [(0., 0.), (w, 0.), (0., h), (w, h)].iter()
+        .map(|&(x, y): &(f64, f64)| ((x - cx) / fx).hypot((y - cy) / fy))
+        .fold(0_f64, f64::max);
+
+    let coefficients = &calibration.distortion_coefficients;
+    let values: Vec<f64> = (0..coefficients.total().unwrap_or(0) as i32)
+        .map(|i| coefficients.at::<f64>(i).map(|v| *v).unwrap_or(0.))
+        .collect();
+    // missing higher order coefficients are zero
+    let d = |i: usize| values.get(i).cloned().unwrap_or(0.);
+
+    // distorted radius as a function of the model's radial parameter, and how far that
+    // parameter can sensibly go
+    let (distort, limit): (Box<dyn Fn(f64) -> f64>, f64) = match calibration.distortion_model {
+        DistortionModel::RadialTangential => (Box::new(|r: f64| {
+            let r2 = r * r;
+            r * (1. + d(0) * r2 + d(1) * r2 * r2 + d(4) * r2 * r2 * r2)
+                / (1. + d(5) * r2 + d(6) * r2 * r2 + d(7) * r2 * r2 * r2)
+        }), corner_radius * 100.),
+        // parameter is the angle from the optical axis
+        DistortionModel::Fisheye => (Box::new(|theta: f64| {
+            let t2 = theta * theta;
+            theta * (1. + d(0) * t2 + d(1) * t2 * t2 + d(2) * t2 * t2 * t2 + d(3) * t2 * t2 * t2 * t2)
+        }), std::f64::consts::PI),
+    };
+
+    let step = corner_radius / 1000.;
+    let (mut r, mut last) = (0_f64, 0_f64);
+    while last < corner_radius {
+        r += step;
+        if r > limit {
+            debug!("distorted radius never reaches the frame corner ({:.3})", corner_radius);
+            return false;
+        }
+        let radius = distort(r);
+        if !radius.is_finite() || radius <= last {
+            debug!("radial distortion folds back at a distorted radius of {:.3}, before the frame corner at {:.3}", last, corner_radius);
+            return false;
+        }
+        last = radius;
+    }
+    true
+}
+
+/// Detect a physical checkerboard in a photo, solve for its pose using the calibration and
+/// return the RMS reprojection error in pixels. `pattern_size` is the number of internal corners.
+pub fn reprojection_error(calibration: &Calibration, photo_fname: &str, pattern_size: Size) -> Result<f64, String> {
+    let photo = imgcodecs::imread(photo_fname, imgcodecs::IMREAD_GRAYSCALE).map_err(|e| e.to_string())?;
+    if photo.empty().map_err(|e| e.to_string())? {
+        return Err(format!("failed to read checkerboard photo {}", photo_fname));
+    }
+    if photo.cols() != calibration.image_width || photo.rows() != calibration.image_height {
+        return Err(format!(
+            "checkerboard photo is {}x{} but the calibration is for {}x{}",
+            photo.cols(), photo.rows(), calibration.image_width, calibration.image_height
+        ));
+    }
+
+    let mut corners = VectorOfPoint2f::new();
+    let found = find_chessboard_corners(&photo, pattern_size, &mut corners, CALIB_CB_ADAPTIVE_THRESH | CALIB_CB_NORMALIZE_IMAGE)
+        .map_err(|e| e.to_string())?;
+    if !found {
+        return Err(format!("{}x{} checkerboard not found in {}", pattern_size.width, pattern_size.height, photo_fname));
+    }
+    corner_sub_pix(&photo, &mut corners, Size::new(11, 11), Size::new(-1, -1), TermCriteria::new(3, 30, 0.01f64).unwrap())
+        .map_err(|e| e.to_string())?;
+
+    // unit squares, the error in pixels doesn't depend on the physical square size
+    let mut object_points = VectorOfPoint3f::new();
+    for y in 0..pattern_size.height {
+        for x in 0..pattern_size.width {
+            object_points.push(Point3f::new(x as f32, y as f32, 0.));
+        }
+    }
+
+    let solve = || -> opencv::Result<VectorOfPoint2f> {
+        let mut rvec = Mat::default()?;
+        let mut tvec = Mat::default()?;
+        let mut projected = VectorOfPoint2f::new();
+        match calibration.distortion_model {
+            DistortionModel::RadialTangential => {
+                solve_pnp(&object_points, &corners, &calibration.camera_matrix, &calibration.distortion_coefficients, &mut rvec, &mut tvec, false, SOLVEPNP_ITERATIVE)?;
+                project_points(&object_points, &rvec, &tvec, &calibration.camera_matrix, &calibration.distortion_coefficients, &mut projected, &mut Mat::default()?, 0.)?;
+            }
+            DistortionModel::Fisheye => {
+                // solve on undistorted pixel positions, then project back through the fisheye model
+                let mut undistorted = VectorOfPoint2f::new();
+                fisheye_undistort_points(&corners, &mut undistorted, &calibration.camera_matrix, &calibration.distortion_coefficients, &Mat::default()?, &calibration.camera_matrix)?;
+                solve_pnp(&object_points, &undistorted, &calibration.camera_matrix, &Mat::default()?, &mut rvec, &mut tvec, false, SOLVEPNP_ITERATIVE)?;
+                let mut normalized = VectorOfPoint2f::new();
+                project_points(&object_points, &rvec, &tvec, &Matx33d::eye(), &Mat::default()?, &mut normalized, &mut Mat::default()?, 0.)?;
+                fisheye_distort_points(&normalized, &mut projected, &calibration.camera_matrix, &calibration.distortion_coefficients, 0.)?;
+            }
+        }
+        Ok(projected)
+    };
+    let projected = solve().map_err(|e| e.to_string())?;
+
+    let mut sum_sq = 0_f64;
+    for (detected, reprojected) in corners.iter().zip(projected.iter()) {
+        let dx = (detected.x - reprojected.x) as f64;
+        let dy = (detected.y - reprojected.y) as f64;
+        sum_sq += dx * dx + dy * dy;
+    }
+    Ok((sum_sq / corners.len() as f64).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str, contents: &str) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join(name);
+        fs::write(&fname, contents).unwrap();
+        (dir, fname.to_str().unwrap().to_string())
+    }
+
+    fn assert_intrinsics(calibration: &Calibration, expected: [f64; 9], width: i32, height: i32) {
+        for (i, &value) in expected.iter().enumerate() {
+            let actual = *calibration.camera_matrix.get((i as i32 / 3, i as i32 % 3)).unwrap();
+            assert!((actual - value).abs() < 1e-9, "camera matrix element {} is {}, expected {}", i, actual, value);
+        }
+        assert_eq!((calibration.image_width, calibration.image_height), (width, height));
+    }
+
+    fn calibration(fx: f64, fy: f64, cx: f64, cy: f64, distortion: &[f64]) -> Calibration {
+        Calibration::new(pinhole_matrix(fx, fy, cx, cy, 0.), distortion, DistortionModel::RadialTangential, 1920, 1080)
+    }
+
+    #[test]
+    fn colmap_shifts_principal_point_half_a_pixel() {
+        let (_dir, fname) = fixture("cameras.txt", "# Camera list with one line of data per camera:\n\
+            1 PINHOLE 640 480 500 510 320 240\n\
+            2 SIMPLE_RADIAL 1280 720 900 640 360 -0.05\n");
+        let first = load_colmap_cameras(&fname, None).unwrap();
+        assert_intrinsics(&first, [500., 0., 319.5, 0., 510., 239.5, 0., 0., 1.], 640, 480);
+
+        let second = load_colmap_cameras(&fname, Some("2")).unwrap();
+        assert_intrinsics(&second, [900., 0., 639.5, 0., 900., 359.5, 0., 0., 1.], 1280, 720);
+        assert_eq!(*second.distortion_coefficients.at::<f64>(0).unwrap(), -0.05);
+    }
+
+    #[test]
+    fn kalibr_intrinsics_are_used_as_is() {
+        let (_dir, fname) = fixture("camchain.yaml", "cam0:\n\
+            \x20 camera_model: pinhole\n\
+            \x20 intrinsics: [461.6, 460.2, 362.7, 248.1]\n\
+            \x20 distortion_model: radtan\n\
+            \x20 distortion_coeffs: [-0.28, 0.07, 0.0002, 0.00002]\n\
+            \x20 resolution: [752, 480]\n\
+            \x20 rostopic: /cam0/image_raw\n");
+        let calibration = load_kalibr_camchain(&fname, None).unwrap();
+        assert_intrinsics(&calibration, [461.6, 0., 362.7, 0., 460.2, 248.1, 0., 0., 1.], 752, 480);
+        assert_eq!(calibration.distortion_model, DistortionModel::RadialTangential);
+    }
+
+    #[test]
+    fn matlab_json_transposes_intrinsic_matrix_and_shifts_a_pixel() {
+        let (_dir, fname) = fixture("params.json", r#"{
+            "IntrinsicMatrix": [[800, 0, 0], [2, 810, 0], [321, 241, 1]],
+            "ImageSize": [480, 640],
+            "RadialDistortion": [-0.2, 0.1]
+        }"#);
+        let calibration = load_matlab_json(&fname).unwrap();
+        assert_intrinsics(&calibration, [800., 2., 320., 0., 810., 240., 0., 0., 1.], 640, 480);
+    }
+
+    #[test]
+    fn matlab_csv_image_size_is_rows_then_columns() {
+        let (_dir, fname) = fixture("params.csv", "Property,Value1,Value2\n\
+            FocalLength,800,810\n\
+            PrincipalPoint,321,241\n\
+            ImageSize,480,640\n\
+            RadialDistortion,-0.2,0.1\n");
+        let calibration = load_matlab_csv(&fname).unwrap();
+        assert_intrinsics(&calibration, [800., 0., 320., 0., 810., 240., 0., 0., 1.], 640, 480);
+    }
+
+    #[test]
+    fn accepts_plausible_intrinsics() {
+        let good = calibration(1000., 1000., 959.5, 539.5, &[-0.1, 0.01, 0., 0., 0.]);
+        assert!(radial_distortion_is_monotonic(&good));
+        assert_eq!(validate(&good, None), Ok(()));
+        // 2 * atan(540 / 1000) is 56.7 degrees
+        assert_eq!(validate(&good, Some(57.)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_principal_point_outside_image() {
+        let problems = validate(&calibration(1000., 1000., 2100., 539.5, &[0.; 4]), None).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("principal point"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn rejects_mismatched_focal_lengths() {
+        let problems = validate(&calibration(1000., 1200., 959.5, 539.5, &[0.; 4]), None).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("fx"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn rejects_focal_length_for_a_different_lens() {
+        let problems = validate(&calibration(1000., 1000., 959.5, 539.5, &[0.; 4]), Some(90.)).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("field of view"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn rejects_folding_radial_distortion() {
+        // r(1 - 0.5r^2) peaks at r = 0.82, well short of the corner at r = 1.1
+        let folding = calibration(1000., 1000., 959.5, 539.5, &[-0.5, 0., 0., 0., 0.]);
+        assert!(!radial_distortion_is_monotonic(&folding));
+        let problems = validate(&folding, None).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("monotonic"), "{}", problems[0]);
+    }
 }
@@ -0,0 +1,171 @@
+
+use opencv::prelude::*;
+use opencv::core::*;
+use opencv::photo::{create_calibrate_debevec, CalibrateCRF};
+use opencv::types::VectorOfMat;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+use log::info;
+
+/// Inverse response curve of a camera, i.e. the relative light that produced each 8 bit
+/// pixel value, per channel. Each channel is scaled so 255 maps to 1.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CameraResponse {
+    pub red: Vec<f32>,
+    pub green: Vec<f32>,
+    pub blue: Vec<f32>,
+}
+
+impl CameraResponse {
+    pub fn load(fname: &str) -> Result<CameraResponse, String> {
+        let file = File::open(fname).map_err(|e| format!("failed to open {}: {}", fname, e))?;
+        let response: CameraResponse = serde_json::from_reader(file).map_err(|e| format!("failed to parse {}: {}", fname, e))?;
+        if response.red.len() != 256 || response.green.len() != 256 || response.blue.len() != 256 {
+            return Err(format!("{} should have 256 values per channel", fname));
+        }
+        Ok(response)
+    }
+
+    pub fn save(&self, fname: &str) -> Result<(), String> {
+        let file = File::create(fname).map_err(|e| format!("failed to create {}: {}", fname, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| format!("failed to write {}: {}", fname, e))
+    }
+
+    /// Convert an 8 bit BGR photo to 32 bit float BGR proportional to the light that reached
+    /// the camera, scaled so 255 stays 255
+    pub fn linearize(&self, photo: &Mat) -> opencv::Result<Mat> {
+        let mut table = Mat::new_rows_cols_with_default(1, 256, CV_32FC3, Scalar::all(0.))?;
+        for i in 0..256 {
+            *table.at_2d_mut::<Vec3f>(0, i as i32)? = Vec3f::from([self.blue[i] * 255., self.green[i] * 255., self.red[i] * 255.]);
+        }
+        let mut linear = Mat::default()?;
+        lut(photo, &table, &mut linear)?;
+        Ok(linear)
+    }
+}
+
+/// Exposure time in seconds from a shutter speed as cameras report it, "1/30", "0.5" or "2"
+pub fn parse_exposure(input: &str) -> Result<f32, String> {
+    let input = input.trim().trim_end_matches('s');
+    let seconds = match input.find('/') {
+        Some(i) => {
+            let numerator: f32 = input[..i].parse().map_err(|_| format!("invalid shutter speed {}", input))?;
+            let denominator: f32 = input[i + 1..].parse().map_err(|_| format!("invalid shutter speed {}", input))?;
+            numerator / denominator
+        }
+        None => input.parse().map_err(|_| format!("invalid shutter speed {}", input))?
+    };
+    if !(seconds > 0.) || !seconds.is_finite() {
+        return Err(format!("invalid shutter speed {}", input));
+    }
+    Ok(seconds)
+}
+
+/// Recover the inverse response from photos of a static scene taken at different exposure
+/// times (seconds), using Debevec's method
+pub fn recover(photos: &[Mat], exposure_times: &[f32]) -> opencv::Result<CameraResponse> {
+    assert!(photos.len() == exposure_times.len() && photos.len() >= 2, "response recovery needs at least two exposures");
+    let mut images = VectorOfMat::new();
+    for photo in photos.iter() {
+        images.push(photo.clone()?);
+    }
+    let times = Mat::from_slice(exposure_times)?;
+    let mut curve = Mat::default()?;
+    let mut debevec = create_calibrate_debevec(70, 10., false)?;
+    debevec.process(&images, &mut curve, &times)?;
+
+    // curve is 256x1 BGR, with an arbitrary scale per channel
+    let mut channels = [vec![], vec![], vec![]]; // red, green, blue
+    for i in 0..256 {
+        let bgr = curve.at_2d::<Vec3f>(i, 0)?;
+        for c in 0..3 {
+            channels[c].push(bgr[2 - c]);
+        }
+    }
+    for values in channels.iter_mut() {
+        let top = values[255].max(1e-6);
+        for v in values.iter_mut() {
+            *v /= top;
+        }
+    }
+    let [red, green, blue] = channels;
+    info!("recovered camera response from {} exposures, mid grey is {:.3}, {:.3}, {:.3} of white", photos.len(), red[128], green[128], blue[128]);
+    Ok(CameraResponse { red: red, green: green, blue: blue })
+}
+
+/// Where the response curve for a camera calibration file is kept: next to it, with any
+/// `#<camera>` selector folded into the name
+pub fn response_path(camera_cal_fname: &str) -> String {
+    match camera_cal_fname.rfind('#') {
+        Some(i) => format!("{}.{}.response.json", &camera_cal_fname[..i], &camera_cal_fname[i + 1..]),
+        None => format!("{}.response.json", camera_cal_fname)
+    }
+}
+
+/// Load the response curve for a camera calibration file, if one has been recovered
+pub fn load_for_calibration(camera_cal_fname: &str) -> Result<Option<CameraResponse>, String> {
+    let fname = response_path(camera_cal_fname);
+    if !Path::new(&fname).exists() {
+        return Ok(None);
+    }
+    info!("camera response curve loaded from {}", fname);
+    CameraResponse::load(&fname).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Photo of a scene whose radiance rises smoothly across it, through a camera with the
+    /// given gamma, clipping at white
+    fn exposed(exposure_time: f32, gamma: f32) -> Mat {
+        let size = 64;
+        let mut photo = Mat::new_rows_cols_with_default(size, size, CV_8UC3, Scalar::all(0.)).unwrap();
+        for y in 0..size {
+            for x in 0..size {
+                // radiance from 0.02 to 4, log spaced
+                let radiance = 0.02 * 200_f32.powf((y * size + x) as f32 / (size * size - 1) as f32);
+                let value = ((radiance * exposure_time).min(1.).powf(1. / gamma) * 255.).round() as u8;
+                *photo.at_2d_mut::<Vec3b>(y, x).unwrap() = Vec3b::from([value, value, value]);
+            }
+        }
+        photo
+    }
+
+    #[test]
+    fn recovers_a_known_gamma() {
+        let times = [1. / 16., 1. / 8., 1. / 4., 1. / 2., 1.];
+        let photos: Vec<Mat> = times.iter().map(|&t| exposed(t, 2.2)).collect();
+        let response = recover(&photos, &times).unwrap();
+        for channel in [&response.red, &response.green, &response.blue].iter() {
+            for i in (32..240).step_by(16) {
+                assert!(channel[i] > channel[i - 16], "response isn't increasing at {}", i);
+            }
+            // only ratios are meaningful, the curve's scale is arbitrary
+            let ratio = channel[192] / channel[96];
+            assert!((ratio / 2_f32.powf(2.2) - 1.).abs() < 0.25, "ratio {} expected {}", ratio, 2_f32.powf(2.2));
+        }
+    }
+
+    #[test]
+    fn linearizes_each_channel_with_its_own_curve() {
+        let response = CameraResponse {
+            red: (0..256).map(|i| (i as f32 / 255.).powf(2.2)).collect(),
+            green: (0..256).map(|i| i as f32 / 255.).collect(),
+            blue: (0..256).map(|i| (i as f32 / 255.).powi(2)).collect(),
+        };
+        let mut photo = Mat::new_rows_cols_with_default(1, 2, CV_8UC3, Scalar::all(0.)).unwrap();
+        *photo.at_2d_mut::<Vec3b>(0, 0).unwrap() = Vec3b::from([128, 128, 128]);
+        *photo.at_2d_mut::<Vec3b>(0, 1).unwrap() = Vec3b::from([255, 64, 0]);
+        let linear = response.linearize(&photo).unwrap();
+
+        let grey = linear.at_2d::<Vec3f>(0, 0).unwrap();
+        let expected = [(128_f32 / 255.).powi(2) * 255., 128., (128_f32 / 255.).powf(2.2) * 255.];
+        for c in 0..3 {
+            assert!((grey[c] - expected[c]).abs() < 1e-3, "channel {} is {} expected {}", c, grey[c], expected[c]);
+        }
+        let colour = linear.at_2d::<Vec3f>(0, 1).unwrap();
+        assert!((colour[0] - 255.).abs() < 1e-3 && (colour[1] - 64.).abs() < 1e-3 && colour[2].abs() < 1e-3, "{} {} {}", colour[0], colour[1], colour[2]);
+    }
+}
@@ -0,0 +1,344 @@
+
+use opencv::prelude::*;
+use opencv::core::*;
+use opencv::imgproc::*;
+use opencv::imgcodecs;
+use opencv::types::VectorOfi32;
+use glm::{vec2, dot, length, normalize};
+use serde::Serialize;
+use std::f32::consts;
+use std::fs::{self, File};
+use std::path::Path;
+use log::info;
+use super::Resolution;
+use super::blend::Coverage;
+use super::surfaces::SurfaceType;
+
+/// Coverage report settings
+#[derive(Clone, Debug)]
+pub struct CoverageOptions {
+    /// directory to write the images and summary to
+    pub dir: String,
+    /// width and height of the domemaster images
+    pub size: i32,
+    /// parts of the dome with fewer projector pixels per degree than this count as low resolution
+    pub min_pixels_per_degree: f32,
+}
+
+impl CoverageOptions {
+    pub fn new(dir: &str) -> CoverageOptions {
+        CoverageOptions { dir: dir.to_string(), size: 512, min_pixels_per_degree: 10. }
+    }
+}
+
+/// One projector's calibrated chessboard corners on the dome
+pub struct Footprint<'a> {
+    pub id: &'a str,
+    /// row-major over a `warp_res` chessboard
+    pub scene_coords: &'a [glm::Vec3],
+    pub warp_res: Resolution,
+    pub projector_res: Resolution,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct DensityStats {
+    pub min: f32,
+    pub mean: f32,
+    pub max: f32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProjectorCoverage {
+    pub id: String,
+    /// share of the dome this projector lights
+    pub covered: f32,
+    pub pixels_per_degree: Option<DensityStats>,
+}
+
+/// Summary of how the projectors cover the dome. Shares are of the dome's solid angle, not
+/// of the domemaster's pixels.
+#[derive(Serialize, Debug)]
+pub struct CoverageReport {
+    /// share of the dome lit by no projector, by exactly one, by exactly two and so on
+    pub coverage: Vec<f32>,
+    /// share lit by no projector
+    pub gaps: f32,
+    /// share lit by more than one projector
+    pub overlap: f32,
+    /// pixels per degree, seen from the eye, of the sharpest projector at each covered point
+    pub pixels_per_degree: Option<DensityStats>,
+    /// share lit, but with fewer than `min_pixels_per_degree`
+    pub low_density: f32,
+    pub min_pixels_per_degree: f32,
+    pub projectors: Vec<ProjectorCoverage>,
+}
+
+/// Projector pixels per degree for each cell of a projector's chessboard
+struct Density {
+    values: Vec<f32>,
+    cols: usize,
+    rows: usize,
+}
+
+impl Density {
+    fn new(footprint: &Footprint, eye: glm::Vec3) -> Density {
+        let (cols, rows) = (footprint.warp_res.width as usize, footprint.warp_res.height as usize);
+        // corners are (i + 1) / (cols + 1) across the output
+        let pixels_x = footprint.projector_res.width as f32 / (cols + 1) as f32;
+        let pixels_y = footprint.projector_res.height as f32 / (rows + 1) as f32;
+        let scene = footprint.scene_coords;
+        let angle = |a: usize, b: usize| {
+            let cos = dot(normalize(scene[a] - eye), normalize(scene[b] - eye));
+            cos.max(-1.).min(1.).acos().to_degrees().max(1e-4)
+        };
+        let mut values = vec![];
+        for j in 0..rows - 1 {
+            for i in 0..cols - 1 {
+                let k = j * cols + i;
+                let across = (angle(k, k + 1) + angle(k + cols, k + cols + 1)) * 0.5;
+                let down = (angle(k, k + cols) + angle(k + 1, k + cols + 1)) * 0.5;
+                values.push((pixels_x / across + pixels_y / down) * 0.5);
+            }
+        }
+        Density { values: values, cols: cols, rows: rows }
+    }
+
+    /// Density at a normalized output position, beyond the outer corners taken from the
+    /// nearest cell
+    fn at(&self, uv: glm::Vec2) -> f32 {
+        let i = ((uv.x * (self.cols + 1) as f32).floor() as isize - 1).max(0).min(self.cols as isize - 2) as usize;
+        let j = ((uv.y * (self.rows + 1) as f32).floor() as isize - 1).max(0).min(self.rows as isize - 2) as usize;
+        self.values[j * (self.cols - 1) + i]
+    }
+}
+
+/// Running min, max and weighted mean
+#[derive(Default)]
+struct Stats {
+    min: f32,
+    max: f32,
+    sum: f64,
+    weight: f64,
+}
+
+impl Stats {
+    fn add(&mut self, value: f32, weight: f32) {
+        if self.weight == 0. {
+            self.min = value;
+            self.max = value;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += (value * weight) as f64;
+        self.weight += weight as f64;
+    }
+
+    fn finish(&self) -> Option<DensityStats> {
+        if self.weight == 0. {
+            return None;
+        }
+        Some(DensityStats { min: self.min, mean: (self.sum / self.weight) as f32, max: self.max })
+    }
+}
+
+/// Colours for the coverage image (BGR): uncovered, one projector, two, three or more
+const COVERAGE_COLOURS: [[u8; 3]; 4] = [[0, 0, 255], [0, 160, 0], [0, 220, 220], [255, 128, 0]];
+
+/// Render the coverage of the dome in domemaster space and write `coverage.png` (projectors
+/// lighting each point), `density.png` (pixels per degree heatmap), `gaps.png` (white where
+/// nothing is projected) and `coverage.json` to the options' directory. The domemaster layout
+/// matches `--warp-mode domemaster`.
+pub fn write_report(surface: &SurfaceType, footprints: &[Footprint], eye: glm::Vec3, options: &CoverageOptions) -> Result<CoverageReport, String> {
+    match surface {
+        SurfaceType::HemisphericalDome{..} => (),
+        _ => return Err(String::from("coverage report needs a dome surface")),
+    }
+    let coverages: Vec<Coverage> = footprints.iter()
+        .map(|footprint| Coverage::new(surface, footprint.scene_coords, footprint.warp_res))
+        .collect();
+    let densities: Vec<Density> = footprints.iter().map(|footprint| Density::new(footprint, eye)).collect();
+
+    let size = options.size;
+    // projectors lighting each pixel, None outside the dome
+    let mut counts: Vec<Option<usize>> = vec![None; (size * size) as usize];
+    let mut best = vec![0_f32; (size * size) as usize];
+    let mut histogram = vec![0_f64; footprints.len() + 1];
+    let mut covered = vec![0_f64; footprints.len()];
+    let mut projector_stats: Vec<Stats> = footprints.iter().map(|_| Stats::default()).collect();
+    let mut stats = Stats::default();
+    let (mut total, mut low_density) = (0_f64, 0_f64);
+    for y in 0..size {
+        for x in 0..size {
+            let chart = vec2((x as f32 + 0.5) / size as f32 * 2. - 1., (y as f32 + 0.5) / size as f32 * 2. - 1.);
+            let r = length(chart);
+            if r > 1. {
+                continue;
+            }
+            // solid angle per domemaster pixel falls off away from the zenith
+            let polar = r * consts::PI / 2.;
+            let weight = if polar < 1e-6 { 1. } else { polar.sin() / polar };
+            total += weight as f64;
+
+            let pixel = (y * size + x) as usize;
+            let mut count = 0;
+            for (p, coverage) in coverages.iter().enumerate() {
+                if let Some(uv) = coverage.locate(chart) {
+                    let density = densities[p].at(uv);
+                    count += 1;
+                    best[pixel] = best[pixel].max(density);
+                    covered[p] += weight as f64;
+                    projector_stats[p].add(density, weight);
+                }
+            }
+            counts[pixel] = Some(count);
+            histogram[count] += weight as f64;
+            if count > 0 {
+                stats.add(best[pixel], weight);
+                if best[pixel] < options.min_pixels_per_degree {
+                    low_density += weight as f64;
+                }
+            }
+        }
+    }
+
+    let share = |w: f64| (w / total.max(1e-9)) as f32;
+    let report = CoverageReport {
+        coverage: histogram.iter().map(|&w| share(w)).collect(),
+        gaps: share(histogram[0]),
+        overlap: share(histogram.get(2..).map(|counts| counts.iter().sum()).unwrap_or(0.)),
+        pixels_per_degree: stats.finish(),
+        low_density: share(low_density),
+        min_pixels_per_degree: options.min_pixels_per_degree,
+        projectors: footprints.iter().enumerate().map(|(p, footprint)| ProjectorCoverage {
+            id: footprint.id.to_string(),
+            covered: share(covered[p]),
+            pixels_per_degree: projector_stats[p].finish(),
+        }).collect(),
+    };
+    info!("{:.1}% of the dome is uncovered, {:.1}% overlapped, {:.1}% below {} pixels per degree",
+        report.gaps * 100., report.overlap * 100., report.low_density * 100., options.min_pixels_per_degree);
+
+    let max_density = report.pixels_per_degree.map(|s| s.max).unwrap_or(1.);
+    let dir = options.dir.as_str();
+    fs::create_dir_all(dir).map_err(|e| format!("failed to create {}: {}", dir, e))?;
+    write_images(size, &counts, &best, max_density, dir).map_err(|e| format!("failed to write coverage images: {}", e))?;
+    let fname = Path::new(dir).join("coverage.json");
+    let file = File::create(&fname).map_err(|e| format!("failed to create {}: {}", fname.display(), e))?;
+    serde_json::to_writer_pretty(file, &report).map_err(|e| format!("failed to write {}: {}", fname.display(), e))?;
+    info!("coverage report written to {}", dir);
+    Ok(report)
+}
+
+fn write_images(size: i32, counts: &[Option<usize>], best: &[f32], max_density: f32, dir: &str) -> opencv::Result<()> {
+    let mut coverage = Mat::new_rows_cols_with_default(size, size, CV_8UC3, Scalar::all(0.))?;
+    let mut gaps = Mat::new_rows_cols_with_default(size, size, CV_8UC1, Scalar::all(0.))?;
+    let mut levels = Mat::new_rows_cols_with_default(size, size, CV_8UC1, Scalar::all(0.))?;
+    for y in 0..size {
+        for x in 0..size {
+            let pixel = (y * size + x) as usize;
+            if let Some(count) = counts[pixel] {
+                *coverage.at_2d_mut::<Vec3b>(y, x)? = Vec3b::from(COVERAGE_COLOURS[count.min(COVERAGE_COLOURS.len() - 1)]);
+                if count == 0 {
+                    *gaps.at_2d_mut::<u8>(y, x)? = 255;
+                }
+                *levels.at_2d_mut::<u8>(y, x)? = (best[pixel] / max_density.max(1e-6) * 255.).max(0.).min(255.) as u8;
+            }
+        }
+    }
+    let mut density = Mat::default()?;
+    apply_color_map(&levels, &mut density, COLORMAP_JET)?;
+    // black outside the dome and in the gaps
+    for y in 0..size {
+        for x in 0..size {
+            match counts[(y * size + x) as usize] {
+                Some(count) if count > 0 => (),
+                _ => *density.at_2d_mut::<Vec3b>(y, x)? = Vec3b::from([0, 0, 0]),
+            }
+        }
+    }
+
+    for (name, image) in [("coverage.png", &coverage), ("density.png", &density), ("gaps.png", &gaps)].iter() {
+        let fname = Path::new(dir).join(name);
+        imgcodecs::imwrite(fname.to_str().unwrap(), *image, &VectorOfi32::new())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glm::vec3;
+
+    /// Chessboard corners on a unit dome, evenly spaced in domemaster chart coordinates
+    fn corners(min: glm::Vec2, max: glm::Vec2, warp_res: Resolution) -> Vec<glm::Vec3> {
+        let (cols, rows) = (warp_res.width, warp_res.height);
+        let mut corners = vec![];
+        for j in 0..rows {
+            for i in 0..cols {
+                let chart = vec2(min.x + (max.x - min.x) * i as f32 / (cols - 1) as f32, min.y + (max.y - min.y) * j as f32 / (rows - 1) as f32);
+                let r = length(chart);
+                let polar = r * consts::PI / 2.;
+                let horizontal = if r < 1e-6 { 0. } else { polar.sin() / r };
+                corners.push(vec3(chart.x * horizontal, polar.cos(), chart.y * horizontal));
+            }
+        }
+        corners
+    }
+
+    /// Share of the dome's solid angle inside a rectangle of the domemaster chart
+    fn solid_angle_share(min: glm::Vec2, max: glm::Vec2) -> f32 {
+        let steps = 400;
+        let (dx, dy) = ((max.x - min.x) as f64 / steps as f64, (max.y - min.y) as f64 / steps as f64);
+        let mut sum = 0_f64;
+        for j in 0..steps {
+            for i in 0..steps {
+                let x = min.x as f64 + (i as f64 + 0.5) * dx;
+                let y = min.y as f64 + (j as f64 + 0.5) * dy;
+                let polar = (x * x + y * y).sqrt() * std::f64::consts::PI / 2.;
+                sum += if polar < 1e-9 { 1. } else { polar.sin() / polar } * dx * dy;
+            }
+        }
+        // the whole domemaster disc integrates to 8 / pi
+        (sum * std::f64::consts::PI / 8.) as f32
+    }
+
+    #[test]
+    fn two_projectors_overlap_and_leave_gaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = CoverageOptions::new(dir.path().to_str().unwrap());
+        options.min_pixels_per_degree = 15.;
+        let dome = SurfaceType::HemisphericalDome { radius: 1. };
+        // 7x7 corners 0.1 apart in the chart, 9 degrees seen from the centre. Corners are
+        // 1440 / 8 = 180 pixels apart on the left projector, 90 on the right.
+        let warp_res = Resolution { width: 7, height: 7 };
+        let left = corners(vec2(-0.5, -0.3), vec2(0.1, 0.3), warp_res);
+        let right = corners(vec2(-0.1, -0.3), vec2(0.5, 0.3), warp_res);
+        let footprints = [
+            Footprint { id: "left", scene_coords: &left, warp_res: warp_res, projector_res: Resolution { width: 1440, height: 1440 } },
+            Footprint { id: "right", scene_coords: &right, warp_res: warp_res, projector_res: Resolution { width: 720, height: 720 } },
+        ];
+        let report = write_report(&dome, &footprints, vec3(0., 0., 0.), &options).unwrap();
+
+        // the frames reach one corner spacing past the outer corners
+        let overlap = solid_angle_share(vec2(-0.2, -0.4), vec2(0.2, 0.4));
+        let covered = solid_angle_share(vec2(-0.6, -0.4), vec2(0.6, 0.4));
+        let right_only = solid_angle_share(vec2(0.2, -0.4), vec2(0.6, 0.4));
+        assert_eq!(report.coverage.len(), 3);
+        assert!((report.overlap - overlap).abs() < 2e-3, "overlap {} expected {}", report.overlap, overlap);
+        assert!((report.coverage[2] - overlap).abs() < 2e-3);
+        assert!((report.gaps - (1. - covered)).abs() < 2e-3, "gaps {} expected {}", report.gaps, 1. - covered);
+        assert!((report.coverage[1] - (covered - overlap)).abs() < 2e-3);
+        assert!((report.projectors[1].covered - (right_only + overlap)).abs() < 2e-3);
+        // only the right projector's own part is below 15 pixels per degree
+        assert!((report.low_density - right_only).abs() < 2e-3, "low density {} expected {}", report.low_density, right_only);
+
+        // radial spacing is exactly 9 degrees, tangential spacing a little less away from the zenith
+        let left_density = report.projectors[0].pixels_per_degree.unwrap();
+        assert!(left_density.min > 19.5 && left_density.max < 23., "{:?}", left_density);
+        let right_density = report.projectors[1].pixels_per_degree.unwrap();
+        assert!(right_density.min > 9.7 && right_density.max < 11.5, "{:?}", right_density);
+        let best = report.pixels_per_degree.unwrap();
+        assert!((best.max - left_density.max).abs() < 1e-3 && best.min >= right_density.min && best.min < 11.5, "{:?}", best);
+        assert!(dir.path().join("coverage.json").exists());
+    }
+}
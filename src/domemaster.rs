@@ -0,0 +1,105 @@
+
+use glm::*;
+use serde::Serialize;
+use log::info;
+use super::Resolution;
+use super::blend::Coverage;
+use super::surfaces::{self, SurfaceType};
+
+/// Rectangle of the domemaster frame in normalized coordinates, origin bottom-left and y up
+/// like the perspective warp
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// The part of a shared domemaster a projector shows
+#[derive(Clone, Debug)]
+pub struct DomemasterSlice {
+    pub region: Region,
+    /// where each chessboard corner should sample the region, normalized to the region
+    pub warp: Vec<glm::Vec2>,
+}
+
+/// Normalized domemaster position of a surface chart position, origin bottom-left and y up.
+/// The zenith is at the centre of the frame and the horizon touches its edges, with scene x
+/// to the right and z toward the bottom.
+pub fn domemaster_uv(chart: glm::Vec2) -> glm::Vec2 {
+    vec2((1. + chart.x) * 0.5, (1. - chart.y) * 0.5)
+}
+
+/// Work out the domemaster region a projector covers, from its chessboard corners on the
+/// dome, and where each corner falls within it
+pub fn slice(surface: &SurfaceType, scene_coords: &[glm::Vec3], warp_res: Resolution) -> Result<DomemasterSlice, &'static str> {
+    match surface {
+        SurfaceType::HemisphericalDome{..} => (),
+        _ => return Err("domemaster output needs a dome surface"),
+    }
+
+    // the corners are inside the projector frame, the coverage mesh reaches its edges
+    let (min, max) = Coverage::new(surface, scene_coords, warp_res).bounds();
+    let (a, b) = (domemaster_uv(min), domemaster_uv(max));
+    let (x0, y0) = (a.x.min(b.x).max(0.), a.y.min(b.y).max(0.));
+    let (x1, y1) = (a.x.max(b.x).min(1.), a.y.max(b.y).min(1.));
+    if x1 <= x0 || y1 <= y0 {
+        return Err("projector doesn't cover any of the domemaster");
+    }
+    let region = Region { x: x0, y: y0, width: x1 - x0, height: y1 - y0 };
+    info!("projector covers domemaster region {:.3},{:.3} {:.3}x{:.3}", region.x, region.y, region.width, region.height);
+
+    let warp = scene_coords.iter()
+        .map(|&point| domemaster_uv(surfaces::surface_chart(surface, point)))
+        .map(|uv| vec2((uv.x - region.x) / region.width, (uv.y - region.y) / region.height))
+        .collect();
+    Ok(DomemasterSlice { region: region, warp: warp })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3x3 chessboard corners on a unit dome, `spacing` radians apart either side of `centre`
+    fn corners(centre: glm::Vec3, spacing: f32) -> Vec<glm::Vec3> {
+        let right = normalize(cross(centre, vec3(0., 0., 1.)));
+        let down = cross(right, centre);
+        let mut corners = vec![];
+        for j in -1..2 {
+            for i in -1..2 {
+                let v = normalize(centre + right * (spacing * i as f32).tan() + down * (spacing * j as f32).tan());
+                corners.push(v);
+            }
+        }
+        corners
+    }
+
+    #[test]
+    fn zenith_maps_to_the_middle_of_the_frame() {
+        assert!(length(domemaster_uv(surfaces::surface_chart(&SurfaceType::HemisphericalDome { radius: 1. }, vec3(0., 1., 0.))) - vec2(0.5, 0.5)) < 1e-6);
+        // y up, so z toward the bottom of the frame
+        assert!(length(domemaster_uv(vec2(0., 1.)) - vec2(0.5, 0.)) < 1e-6);
+        assert!(length(domemaster_uv(vec2(1., 0.)) - vec2(1., 0.5)) < 1e-6);
+    }
+
+    #[test]
+    fn warp_is_y_up_within_the_region() {
+        let dome = SurfaceType::HemisphericalDome { radius: 1. };
+        // a projector around the zenith
+        let zenith = corners(vec3(0., 1., 0.), 0.2);
+        let overhead = slice(&dome, &zenith, Resolution { width: 3, height: 3 }).unwrap();
+        let region = overhead.region;
+        assert!((region.x + region.width * 0.5 - 0.5).abs() < 1e-4 && (region.y + region.height * 0.5 - 0.5).abs() < 1e-4, "{:?}", region);
+        // the middle corner samples the middle of the region
+        assert!(length(overhead.warp[4] - vec2(0.5, 0.5)) < 1e-4, "{:?}", overhead.warp[4]);
+
+        // half way down toward z lands half way to the bottom of the frame
+        let side = slice(&dome, &corners(normalize(vec3(0., 1., 1.)), 0.1), Resolution { width: 3, height: 3 }).unwrap();
+        let region = side.region;
+        let centre = vec2(region.x + region.width * side.warp[4].x, region.y + region.height * side.warp[4].y);
+        assert!(length(centre - vec2(0.5, 0.25)) < 1e-4, "{:?}", centre);
+        // and the row of corners nearer the zenith samples higher up the region
+        assert!(side.warp[1].y > side.warp[4].y && side.warp[4].y > side.warp[7].y, "{:?}", side.warp);
+    }
+}
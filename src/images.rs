@@ -48,8 +48,9 @@ pub fn encode_image(data: &Mat, format: &str) -> VectorOfu8 {
   encoded
 }
 
-#[allow(dead_code)]
+/// Single colour image, stretched full-screen by the projector
 pub fn pixel_png(r: u8, g: u8, b: u8) -> VectorOfu8 {
-    let mat = Mat::new_size_with_default(Size::new(1, 1), CV_8UC3, Scalar::new(r as f64, g as f64, b as f64, 255.)).unwrap();
+    // OpenCV images are BGR
+    let mat = Mat::new_size_with_default(Size::new(1, 1), CV_8UC3, Scalar::new(b as f64, g as f64, r as f64, 255.)).unwrap();
     encode_image(&mat, ".png")
 }
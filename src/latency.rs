@@ -0,0 +1,99 @@
+
+use opencv::prelude::*;
+use opencv::core::*;
+use super::photo::{Camera, PhotoError};
+use super::images;
+use super::projector::ProjectorControl;
+use serde::Serialize;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use log::{info, debug};
+
+/// Result of flashing solid frames and watching for them in the camera
+#[derive(Serialize, Debug)]
+pub struct LatencyReport {
+    /// time from posting each frame to the first photo showing it, in milliseconds
+    pub latencies_ms: Vec<u64>,
+    pub min_ms: u64,
+    pub median_ms: u64,
+    pub max_ms: u64,
+    /// typical time taken by one capture, which limits the precision of the measurement
+    pub capture_ms: u64,
+    /// value to use for the settle delay before capturing patterns
+    pub suggested_settle_ms: u64,
+}
+
+/// Give up on a frame appearing after this long
+const FLASH_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn mean_brightness(photo: &Mat) -> Result<f64, PhotoError> {
+    let avg = mean(photo, &Mat::default().unwrap()).map_err(|e| PhotoError::Camera(e.to_string()))?;
+    Ok((avg[0] + avg[1] + avg[2]) / 3.)
+}
+
+fn show_solid(projector: &mut dyn ProjectorControl, level: u8) -> Result<(), PhotoError> {
+    let png = images::pixel_png(level, level, level);
+    projector.show_image(&png.to_slice(), "png")
+        .map_err(|e| PhotoError::Camera(format!("failed to show frame: {}", e)))
+}
+
+/// Alternate black and white frames and time how long each takes to appear in the camera.
+/// Latency is measured to the start of the first capture that shows the new frame, so it's
+/// only as precise as the camera is fast.
+pub fn measure(projector: &mut dyn ProjectorControl, camera: &mut dyn Camera, flashes: u32) -> Result<LatencyReport, PhotoError> {
+    // establish black and white levels with plenty of time to settle
+    show_solid(projector, 0)?;
+    sleep(Duration::from_secs(2));
+    let black = mean_brightness(&camera.capture()?)?;
+    show_solid(projector, 255)?;
+    sleep(Duration::from_secs(2));
+    let white = mean_brightness(&camera.capture()?)?;
+    info!("camera brightness is {:.1} for a black frame and {:.1} for a white frame", black, white);
+    if white - black < 10. {
+        return Err(PhotoError::Camera(String::from("camera can't distinguish black and white frames, is it pointing at the projection?")));
+    }
+    let threshold = (black + white) / 2.;
+
+    let mut latencies = vec![];
+    let mut capture_times = vec![];
+    for flash in 0..flashes {
+        // white was shown last, so even flashes go to black
+        let to_white = flash % 2 == 1;
+        let posted = Instant::now();
+        show_solid(projector, if to_white { 255 } else { 0 })?;
+
+        loop {
+            let capture_start = Instant::now();
+            let brightness = mean_brightness(&camera.capture()?)?;
+            capture_times.push(capture_start.elapsed());
+            if (brightness > threshold) == to_white {
+                let latency = capture_start.saturating_duration_since(posted);
+                debug!("flash {} appeared after {:?}", flash, latency);
+                latencies.push(latency.as_millis() as u64);
+                break;
+            }
+            if posted.elapsed() > FLASH_TIMEOUT {
+                return Err(PhotoError::Timeout);
+            }
+        }
+    }
+
+    if latencies.is_empty() {
+        return Err(PhotoError::Camera(String::from("no flashes were measured")));
+    }
+    let mut sorted = latencies.clone();
+    sorted.sort();
+    capture_times.sort();
+    let capture_ms = capture_times[capture_times.len() / 2].as_millis() as u64;
+    let max_ms = *sorted.last().unwrap();
+
+    Ok(LatencyReport {
+        min_ms: sorted[0],
+        median_ms: sorted[sorted.len() / 2],
+        max_ms: max_ms,
+        capture_ms: capture_ms,
+        // the frame may have appeared at any point during the capture before the one that saw it
+        suggested_settle_ms: max_ms + capture_ms,
+        latencies_ms: latencies,
+    })
+}
@@ -8,19 +8,41 @@ use opencv::calib3d::*;
 use glm::*;
 use glm::ext::*;
 use serde_json::json;
-use std::io::prelude::*;
 use std::fmt;
-use log::{info, warn, debug};
+use std::thread::sleep;
+use std::time::Duration;
+use log::{info, warn, error, debug};
 use regex::Regex;
 use lazy_static::*;
 
 mod math;
-mod photo;
+pub mod photo;
+pub mod tether;
+pub mod video_capture;
+pub mod http_camera;
 mod images;
-mod network;
+pub mod network;
 mod locator;
 pub mod surfaces;
-mod camera_calibration;
+pub mod camera_calibration;
+pub mod camera_response;
+pub mod session;
+pub mod projector;
+pub mod local_display;
+pub mod osc;
+pub mod websocket;
+pub mod stub_server;
+pub mod rig;
+pub mod blend;
+pub mod photometric;
+pub mod uplift;
+pub mod refine;
+pub mod domemaster;
+pub mod coverage_report;
+mod latency;
+pub mod simulator;
+
+use projector::ProjectorControl;
 
 pub struct PhysicalCamera {
     pub position: glm::Vec3,
@@ -36,7 +58,43 @@ struct VirtualCamera {
     pub fov: Option<f32> // this is calculated during calibration
 }
 
-#[derive(Clone, Copy)]
+/// How to make sure a pattern is really on screen before it's photographed. Projectors and
+/// media servers that buffer frames otherwise get the previous pattern photographed.
+#[derive(Clone, Copy, Debug)]
+pub struct DisplaySync {
+    /// wait this long after showing (and confirming) a pattern before capturing
+    pub settle: Duration,
+    /// ask the control server to confirm the pattern has been displayed
+    pub handshake: bool,
+    pub handshake_timeout: Duration,
+}
+
+impl Default for DisplaySync {
+    fn default() -> DisplaySync {
+        DisplaySync { settle: Duration::from_millis(0), handshake: false, handshake_timeout: Duration::from_secs(5) }
+    }
+}
+
+/// What a projector's warp samples the content from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WarpMode {
+    /// a perspective virtual camera per projector, looking at the centre of its chessboard
+    Perspective,
+    /// a rectangular region of one domemaster frame shared by every projector. Dome only.
+    Domemaster,
+}
+
+impl WarpMode {
+    pub fn parse(input: &str) -> Result<WarpMode, &'static str> {
+        match input {
+            "perspective" => Ok(WarpMode::Perspective),
+            "domemaster" => Ok(WarpMode::Domemaster),
+            _ => Err("warp mode should be perspective or domemaster")
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Resolution {
     width: i32,
     height: i32
@@ -49,6 +107,10 @@ impl fmt::Display for Resolution {
 }
 
 impl Resolution {
+    pub fn new(width: i32, height: i32) -> Resolution {
+        Resolution { width: width, height: height }
+    }
+
     /// aspect ratio of projector output as a fraction (width/height)
     fn aspect_ratio(&self) -> f32 {
         self.width as f32 / self.height as f32
@@ -67,26 +129,174 @@ impl Resolution {
 }
 
 /// Output camera location relative to a single 6x6 aruco marker at 0,0,0 facing into the Z axis
-pub fn locate_camera(camera_cal_fname: &str, camera: Option<&str>, marker_size: f32) {
-    let calibration = camera_calibration::load_calibration_file(camera_cal_fname).expect("load of calibration XML failed");
-    let camera_type = match camera {
-        Some(url_or_path) => {
-            if url_or_path.starts_with("http") {
-                photo::CameraType::RemoteHttpCamera {url: url_or_path.to_string()}
-            } else {
-                // TODO check early that file exists
-                photo::CameraType::SingleImageFile {path: url_or_path.to_string()}
+pub fn locate_camera(camera_cal_fname: &str, camera: &mut dyn photo::Camera, marker_size: f32) {
+    let calibration = camera_calibration::load_calibration(camera_cal_fname).expect("load of camera calibration failed");
+    let mut photo = camera.capture().expect("failed to capture photo");
+    locator::locate_aruco_marker(&calibration, &mut photo, marker_size);
+}
+
+/// Flash solid frames on the projector and time how long they take to show up in the camera.
+/// Prints a JSON report including a suggested settle delay.
+pub fn measure_latency(projector: &mut dyn ProjectorControl, camera: &mut dyn photo::Camera, flashes: u32) {
+    let report = latency::measure(projector, camera, flashes).expect("latency measurement failed");
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+}
+
+/// Photograph a static scene at each of the given shutter speeds and recover the camera's
+/// inverse response curve, which is saved to `output_fname` (by default next to the camera
+/// calibration file, where photometric calibration picks it up). A replayed session already
+/// holds the bracketed photos, so its shutter speeds aren't set, and `exposures` must be the
+/// ones it was recorded with.
+pub fn calibrate_camera_response(camera_cal_fname: &str, camera: &mut dyn photo::Camera, settings: &photo::CameraSettings, exposures: &[String], output_fname: Option<&str>) -> Result<(), String> {
+    if exposures.len() < 2 {
+        return Err(String::from("response recovery needs at least two exposures"));
+    }
+    let live = camera.metadata().live;
+    let mut photos = vec![];
+    let mut times = vec![];
+    for (i, exposure) in exposures.iter().enumerate() {
+        times.push(camera_response::parse_exposure(exposure)?);
+        if live {
+            let settings = photo::CameraSettings { shutter_speed: Some(exposure.clone()), ..settings.clone() };
+            camera.apply_settings(&settings).map_err(|e| format!("can't bracket exposures with this camera: {}", e))?;
+        }
+        info!("capturing exposure {}", exposure);
+        photos.push(camera.capture_pattern(&format!("exposure-{}", i)).map_err(|e| format!("failed to capture photo: {}", e))?);
+    }
+    let response = camera_response::recover(&photos, &times).map_err(|e| format!("failed to recover camera response: {}", e))?;
+    let fname = output_fname.map(String::from).unwrap_or_else(|| camera_response::response_path(camera_cal_fname));
+    response.save(&fname)?;
+    info!("camera response curve written to {}", fname);
+    Ok(())
+}
+
+/// Check a camera calibration file for implausible intrinsics and, if a photo of a physical
+/// checkerboard is supplied, measure the reprojection error. Returns false if any check failed.
+pub fn validate_calibration(camera_cal_fname: &str, lens_fov: Option<f32>, checkerboard_photo: Option<&str>, pattern_size: Resolution, max_reprojection_error: f64) -> bool {
+    let calibration = camera_calibration::load_calibration(camera_cal_fname).expect("load of camera calibration failed");
+    let mut ok = true;
+
+    match camera_calibration::validate(&calibration, lens_fov) {
+        Ok(()) => info!("intrinsics look plausible"),
+        Err(problems) => {
+            for problem in problems.iter() { error!("{}", problem); }
+            ok = false;
+        }
+    }
+
+    if let Some(fname) = checkerboard_photo {
+        let board_size = Size::new(pattern_size.width, pattern_size.height);
+        match camera_calibration::reprojection_error(&calibration, fname, board_size) {
+            Ok(rms) if rms <= max_reprojection_error => info!("checkerboard reprojection error is {:.3} pixels", rms),
+            Ok(rms) => {
+                error!("checkerboard reprojection error is {:.3} pixels (limit {:.3})", rms, max_reprojection_error);
+                ok = false;
+            }
+            Err(e) => {
+                error!("reprojection check failed: {}", e);
+                ok = false;
             }
         }
-        None => photo::CameraType::TetheredCamera
-    };
-    let photo = photo::capture_photo(camera_type);
-    let mut decoded = imgcodecs::imdecode(&photo, imgcodecs::IMREAD_COLOR).unwrap();
-    locator::locate_aruco_marker(&calibration, &mut decoded, marker_size);
+    }
+
+    ok
+}
+
+/// Run the whole calibration for one projector. The resulting calibration JSON is pushed to
+/// `post_to` (or printed) and returned.
+pub fn produce_calibration(surface: surfaces::SurfaceType, camera_cal_fname: &str, projector: &mut dyn ProjectorControl, display_sync: DisplaySync, camera: &mut dyn photo::Camera, camera_location_fname: Option<&str>, eye_position: glm::Vec3, warp_res: Resolution, projector_res: Resolution, warp_mode: WarpMode, photometric: Option<photometric::PhotometricOptions>, post_to: Option<&mut dyn ProjectorControl>, recorder: Option<&session::SessionRecorder>) -> String {
+    let physical_camera = setup_physical_camera(camera_cal_fname, camera_location_fname, recorder);
+    let mut warp = calibrate_projector(&surface, &physical_camera, projector, &display_sync, camera, eye_position, warp_res, projector_res, "", photometric, recorder);
+    match_colours(std::slice::from_mut(&mut warp));
+    slice_domemaster(&surface, std::slice::from_mut(&mut warp), warp_mode);
+    let json = serde_json::to_string_pretty(&calibration_json(&warp, None)).unwrap();
+    publish_calibration(&json, post_to, recorder);
+    json
 }
 
-pub fn produce_calibration(surface: surfaces::SurfaceType, camera_cal_fname: &str, control_url: Option<&str>, camera: Option<&str>, camera_location_fname: Option<&str>, eye_position: glm::Vec3, warp_res: Resolution, projector_res: Resolution, post_to: Option<&str>) {
-    let calibration = camera_calibration::load_calibration_file(camera_cal_fname).expect("load of calibration XML failed");
+/// One projector of a multi-projector rig
+pub struct ProjectorSetup {
+    /// key for this projector's calibration in the combined document
+    pub id: String,
+    pub projector: Box<dyn ProjectorControl>,
+    pub warp_res: Resolution,
+    pub projector_res: Resolution,
+}
+
+/// Edge blending between overlapping projectors
+pub struct BlendOptions {
+    pub curve: blend::BlendCurve,
+    /// write a `blend-<id>.png` mask for each projector here
+    pub mask_dir: Option<String>,
+    /// write an `uplift-<id>.png` black level uplift image for each projector to the mask
+    /// directory (or the working directory). Needs photometric measurements.
+    pub black_uplift: bool,
+}
+
+/// Calibrate several projectors in one run with a single camera. Every other projector is
+/// blanked while one is being captured. The result is one document with each projector's
+/// calibration under its ID, `{"projectors": {"<id>": {...}, ...}}`, which is pushed to
+/// `post_to` (or printed) and returned. With `refine`, the warps are adjusted together to agree
+/// where projectors overlap and the document gets a `seamError` before and after. With
+/// `blend`, each calibration also gets an `alpha` for every warp vertex. With `coverage`, a
+/// report of how the projectors cover the dome is written as well.
+pub fn produce_multi_calibration(mut surface: surfaces::SurfaceType, camera_cal_fname: &str, projectors: &mut [ProjectorSetup], display_sync: DisplaySync, camera: &mut dyn photo::Camera, camera_location_fname: Option<&str>, eye_position: glm::Vec3, warp_mode: WarpMode, photometric: Option<photometric::PhotometricOptions>, refine: Option<refine::RefineOptions>, blend: Option<&BlendOptions>, coverage: Option<&coverage_report::CoverageOptions>, post_to: Option<&mut dyn ProjectorControl>, recorder: Option<&session::SessionRecorder>) -> String {
+    let physical_camera = setup_physical_camera(camera_cal_fname, camera_location_fname, recorder);
+    let mut warps = vec![];
+
+    for current in 0..projectors.len() {
+        for (i, other) in projectors.iter_mut().enumerate() {
+            if i != current {
+                if let Err(e) = other.projector.clear() {
+                    panic!("failed to blank projector {}: {}", other.id, e);
+                }
+            }
+        }
+        let setup = &mut projectors[current];
+        info!("calibrating projector {}", setup.id);
+        let prefix = format!("{}-", setup.id);
+        warps.push(calibrate_projector(&surface, &physical_camera, setup.projector.as_mut(), &display_sync, camera, eye_position, setup.warp_res, setup.projector_res, &prefix, photometric, recorder));
+    }
+    match_colours(&mut warps);
+    let seam_error = refine.map(|options| refine_warps(&mut surface, &physical_camera, projectors, &mut warps, options));
+    slice_domemaster(&surface, &mut warps, warp_mode);
+
+    let alphas = blend.map(|options| blend_projectors(&surface, projectors, &warps, options));
+    if let Some(options) = coverage {
+        let footprints: Vec<coverage_report::Footprint> = projectors.iter().zip(warps.iter())
+            .map(|(setup, warp)| coverage_report::Footprint {
+                id: &setup.id,
+                scene_coords: &warp.scene_coords,
+                warp_res: warp.warp_res,
+                projector_res: setup.projector_res,
+            })
+            .collect();
+        coverage_report::write_report(&surface, &footprints, eye_position, options).unwrap_or_else(|e| panic!("{}", e));
+    }
+    let mut calibrations = serde_json::Map::new();
+    for (i, (setup, warp)) in projectors.iter().zip(warps.iter()).enumerate() {
+        let alpha = alphas.as_ref().map(|a| a[i].as_slice());
+        calibrations.insert(setup.id.clone(), calibration_json(warp, alpha));
+    }
+
+    let mut document = json!({ "projectors": calibrations });
+    if let Some((before, after)) = seam_error {
+        document["seamError"] = json!({ "before": before, "after": after });
+    }
+    let json = serde_json::to_string_pretty(&document).unwrap();
+    publish_calibration(&json, post_to, recorder);
+    json
+}
+
+fn setup_physical_camera(camera_cal_fname: &str, camera_location_fname: Option<&str>, recorder: Option<&session::SessionRecorder>) -> PhysicalCamera {
+    if let Some(recorder) = recorder {
+        recorder.record_calibration(camera_cal_fname);
+    }
+    let calibration = camera_calibration::load_calibration(camera_cal_fname).expect("load of camera calibration failed");
+    if let Err(problems) = camera_calibration::validate(&calibration, None) {
+        for problem in problems.iter() { error!("{}", problem); }
+        panic!("camera calibration {} failed sanity checks", camera_cal_fname);
+    }
     let mut physical_camera = PhysicalCamera {    
         // camera position (should be suppied by user)
         position: vec3(0., 0., 0.),
@@ -97,17 +307,120 @@ pub fn produce_calibration(surface: surfaces::SurfaceType, camera_cal_fname: &st
     if let Some(fname) = camera_location_fname {
         locator::update_physical_camera_location(&mut physical_camera, fname);
     }
-    let camera_type = match camera {
-        Some(url_or_path) => {
-            if url_or_path.starts_with("http") {
-                photo::CameraType::RemoteHttpCamera {url: url_or_path.to_string()}
-            } else {
-                // TODO check early that file exists
-                photo::CameraType::SingleImageFile {path: url_or_path.to_string()}
-            }
-        }
-        None => photo::CameraType::TetheredCamera
+    physical_camera
+}
+
+/// Bundle adjust the surface, camera and all the warps together, then recalculate their look
+/// at points and render buffer coordinates. Returns the seam error before and after.
+fn refine_warps(surface: &mut surfaces::SurfaceType, physical_camera: &PhysicalCamera, projectors: &[ProjectorSetup], warps: &mut [ProjectorWarp], options: refine::RefineOptions) -> (refine::SeamError, refine::SeamError) {
+    let mut scene_coords: Vec<Vec<glm::Vec3>> = warps.iter().map(|warp| warp.scene_coords.clone()).collect();
+    let grids: Vec<Resolution> = warps.iter().map(|warp| warp.warp_res).collect();
+    let seam_error = {
+        let image_points: Vec<&[glm::Vec2]> = warps.iter().map(|warp| warp.image_points.as_slice()).collect();
+        refine::refine(surface, physical_camera, &image_points, &mut scene_coords, &grids, options)
     };
+    for ((warp, setup), refined) in warps.iter_mut().zip(projectors.iter()).zip(scene_coords.into_iter()) {
+        // the middle of the refined chessboard, on the refined surface
+        let mut centre = vec3(0., 0., 0.);
+        for corner in refined.iter() { centre = centre + *corner }
+        warp.virtual_camera.look_at = Some(surfaces::project_to_surface(surface, centre / refined.len() as f32));
+        warp.uv_coords = generate_uv_warp_and_fov(&refined, &mut warp.virtual_camera, setup.projector_res);
+        warp.scene_coords = refined;
+    }
+    seam_error
+}
+
+/// With domemaster output, find the part of the domemaster each projector shows
+fn slice_domemaster(surface: &surfaces::SurfaceType, warps: &mut [ProjectorWarp], warp_mode: WarpMode) {
+    if warp_mode != WarpMode::Domemaster {
+        return;
+    }
+    for warp in warps.iter_mut() {
+        let slice = domemaster::slice(surface, &warp.scene_coords, warp.warp_res).unwrap_or_else(|e| panic!("{}", e));
+        warp.domemaster = Some(slice);
+    }
+}
+
+/// Path of a file in an output directory, creating the directory if it doesn't exist yet
+fn output_path(dir: &str, name: &str) -> std::path::PathBuf {
+    std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("failed to create {}: {}", dir, e));
+    std::path::Path::new(dir).join(name)
+}
+
+/// Work out blend weights for every projector's warp vertices, and write the masks
+fn blend_projectors(surface: &surfaces::SurfaceType, projectors: &[ProjectorSetup], warps: &[ProjectorWarp], options: &BlendOptions) -> Vec<Vec<f32>> {
+    let coverages: Vec<blend::Coverage> = warps.iter()
+        .map(|warp| blend::Coverage::new(surface, &warp.scene_coords, warp.warp_res))
+        .collect();
+    let mut alphas = vec![];
+    for (i, setup) in projectors.iter().enumerate() {
+        alphas.push(blend::vertex_alphas(&coverages, i, options.curve));
+        if let Some(dir) = options.mask_dir.as_deref() {
+            let fname = output_path(dir, &format!("blend-{}.png", setup.id));
+            let mask = blend::blend_mask(&coverages, i, setup.projector_res, options.curve).expect("failed to compute blend mask");
+            imgcodecs::imwrite(fname.to_str().unwrap(), &mask, &VectorOfi32::new()).expect("failed to write blend mask");
+            info!("wrote blend mask for projector {} to {}", setup.id, fname.display());
+        }
+    }
+
+    if options.black_uplift {
+        let responses: Vec<&photometric::ProjectorResponse> = warps.iter()
+            .map(|warp| warp.response.as_ref().expect("black level uplift needs photometric measurements"))
+            .collect();
+        let floors: Vec<uplift::BlackFloor> = warps.iter().zip(responses.iter())
+            .map(|(warp, response)| uplift::BlackFloor::new(&response.black_samples, warp.warp_res))
+            .collect();
+        let target = uplift::uplift_target(&floors);
+        for (i, setup) in projectors.iter().enumerate() {
+            let fname = output_path(options.mask_dir.as_deref().unwrap_or("."), &format!("uplift-{}.png", setup.id));
+            let image = uplift::uplift_image(&coverages, &floors, &responses, i, setup.projector_res, target).expect("failed to compute black level uplift");
+            imgcodecs::imwrite(fname.to_str().unwrap(), &image, &VectorOfi32::new()).expect("failed to write black level uplift");
+            info!("wrote black level uplift for projector {} to {}", setup.id, fname.display());
+        }
+    }
+    alphas
+}
+
+/// Work out colour matching and brightness flattening for projectors whose colour samples were
+/// captured
+fn match_colours(warps: &mut [ProjectorWarp]) {
+    let samples: Vec<Option<&photometric::ColourSamples>> = warps.iter()
+        .map(|warp| warp.response.as_ref().and_then(|r| r.colour_samples.as_ref()))
+        .collect();
+    if samples.iter().all(|s| s.is_none()) {
+        return;
+    }
+    // corrections are relative to the other projectors, so every one has to be measured
+    let samples: Vec<&photometric::ColourSamples> = samples.into_iter().collect::<Option<_>>()
+        .unwrap_or_else(|| panic!("colour matching needs colour samples from every projector"));
+    let grids: Vec<(i32, i32)> = warps.iter().map(|warp| (warp.warp_res.width, warp.warp_res.height)).collect();
+    let corrections = photometric::match_projectors(&samples, &grids).unwrap_or_else(|e| panic!("colour matching failed: {}", e));
+    for (warp, correction) in warps.iter_mut().zip(corrections.into_iter()) {
+        warp.colour = Some(correction);
+    }
+}
+
+/// Geometry found for one projector
+struct ProjectorWarp {
+    /// chessboard corners in the undistorted camera photo, row-major
+    image_points: Vec<glm::Vec2>,
+    /// chessboard corners on the surface, row-major
+    scene_coords: Vec<glm::Vec3>,
+    /// where each corner should be drawn in the render buffer
+    uv_coords: Vec<glm::Vec2>,
+    virtual_camera: VirtualCamera,
+    warp_res: Resolution,
+    /// measured transfer curves, if photometric calibration was asked for
+    response: Option<photometric::ProjectorResponse>,
+    colour: Option<photometric::ColourCorrection>,
+    /// region of the shared domemaster, replacing the virtual camera, in domemaster mode
+    domemaster: Option<domemaster::DomemasterSlice>,
+}
+
+/// Show the chessboard on one projector, find it in the camera and work out the frustum and
+/// warp. Pattern names are prefixed with `pattern_prefix` so the photos of several projectors
+/// can be told apart.
+fn calibrate_projector(surface: &surfaces::SurfaceType, physical_camera: &PhysicalCamera, projector: &mut dyn ProjectorControl, display_sync: &DisplaySync, camera: &mut dyn photo::Camera, eye_position: glm::Vec3, warp_res: Resolution, projector_res: Resolution, pattern_prefix: &str, photometric: Option<photometric::PhotometricOptions>, recorder: Option<&session::SessionRecorder>) -> ProjectorWarp {
     let mut virtual_camera = VirtualCamera {
         position: eye_position,
         look_at: None,
@@ -117,19 +430,43 @@ pub fn produce_calibration(surface: surfaces::SurfaceType, camera_cal_fname: &st
 
     info!("projector resolution is {}", projector_res);
 
-    let image_points = detect_image_points(&physical_camera, control_url, camera_type, warp_res);
-    let scene_coords = locate_scene_coords(&surface, &physical_camera, &image_points);
-    virtual_camera.look_at = Some(calculate_look_at(&surface, &image_points, &physical_camera));
+    let image_points = detect_image_points(physical_camera, projector, display_sync, camera, warp_res, &format!("{}chessboard", pattern_prefix), recorder);
+    let scene_coords = locate_scene_coords(surface, physical_camera, &image_points);
+    virtual_camera.look_at = Some(calculate_look_at(surface, &image_points, physical_camera));
     let uv_coords = generate_uv_warp_and_fov(&scene_coords, &mut virtual_camera, projector_res);
-    let json = calibration_json_string(&scene_coords, &uv_coords, &virtual_camera, warp_res);
-    if let Some(url) = post_to {
-        network::send_command(&url, "set_calibration", &json);
+
+    let response = photometric.map(|options| {
+        photometric::measure(options, &image_points, |name, r, g, b| {
+            let pattern = format!("{}{}", pattern_prefix, name);
+            let image = images::pixel_png(r, g, b);
+            if let Some(recorder) = recorder {
+                recorder.record_pattern(&pattern, &image.to_slice(), "png");
+            }
+            show_pattern(projector, camera, &image.to_slice(), "png", display_sync);
+            let photo = capture_undistorted(&physical_camera.calibration, camera, &pattern, recorder)?;
+            match &physical_camera.calibration.response {
+                Some(camera_response) => camera_response.linearize(&photo),
+                None => Ok(photo)
+            }
+        }).expect("photometric measurement failed")
+    });
+
+    ProjectorWarp { image_points: image_points, scene_coords: scene_coords, uv_coords: uv_coords, virtual_camera: virtual_camera, warp_res: warp_res, response: response, colour: None, domemaster: None }
+}
+
+fn publish_calibration(json: &str, post_to: Option<&mut dyn ProjectorControl>, recorder: Option<&session::SessionRecorder>) {
+    if let Some(recorder) = recorder {
+        recorder.record_result(json);
+    }
+    if let Some(target) = post_to {
+        if let Err(e) = target.push_calibration(json) {
+            panic!("failed to post calibration: {}", e);
+        }
     } else {
         println!("{}", json);
     }
 }
 
-
 fn calculate_look_at(surface: &surfaces::SurfaceType, image_points: &Vec<glm::Vec2>, physical_camera: &PhysicalCamera) -> glm::Vec3 {
     // possibly naively, we just look_at the center of the chessboard
     let mut avg = vec2(0., 0.);
@@ -165,24 +502,39 @@ fn locate_scene_coords(surface: &surfaces::SurfaceType, physical_camera: &Physic
     scene_coords
 }
 
-fn detect_image_points(physical_camera: &PhysicalCamera, control_url: Option<&str>, camera_type: photo::CameraType, warp_res: Resolution) -> Vec<glm::Vec2> {
-    // show chessboard image on first projector
+fn detect_image_points(physical_camera: &PhysicalCamera, projector: &mut dyn ProjectorControl, display_sync: &DisplaySync, camera: &mut dyn photo::Camera, warp_res: Resolution, pattern: &str, recorder: Option<&session::SessionRecorder>) -> Vec<glm::Vec2> {
+    // show chessboard image on the projector
     let chessboard = images::chessboard_image(warp_res.width, warp_res.height, ".png");
-    match &control_url {
-        Some(url) => {
-            network::post_image(&url, &chessboard.to_slice(), "png").unwrap();
-        },
-        None => {
-            info!("Please display the full-screen chessboard pattern on the projector and press any key");
-            std::io::stdin().bytes().next();
-            info!("Continuing...");
-        }
+    if let Some(recorder) = recorder {
+        recorder.record_pattern(pattern, &chessboard.to_slice(), "png");
     }
+    show_pattern(projector, camera, &chessboard.to_slice(), "png", display_sync);
 
-    let photo = take_undistorted_photo(&physical_camera.calibration, camera_type).expect("failed to take photo");
+    let photo = take_undistorted_photo(&physical_camera.calibration, camera, pattern, recorder).expect("failed to take photo");
     locate_chessboard_corners(&photo, warp_res).expect("failed to locate chessboard corners")
 }
 
+/// Put a full-screen pattern on the projector, then wait until it's safe to photograph it
+fn show_pattern(projector: &mut dyn ProjectorControl, camera: &dyn photo::Camera, image_bytes: &[u8], format: &str, sync: &DisplaySync) {
+    if let Err(e) = projector.show_image(image_bytes, format) {
+        panic!("failed to show pattern: {}", e);
+    }
+    if !camera.metadata().live {
+        debug!("{} camera doesn't photograph a live projector, not waiting for the pattern to be displayed", camera.metadata().backend);
+        return;
+    }
+    if sync.handshake {
+        if let Err(e) = projector.wait_until_displayed(sync.handshake_timeout) {
+            panic!("projector didn't confirm the pattern was displayed: {}", e);
+        }
+    }
+
+    if sync.settle > Duration::from_millis(0) {
+        debug!("waiting {:?} for the projector to settle", sync.settle);
+        sleep(sync.settle);
+    }
+}
+
 fn generate_uv_warp_and_fov(scene_coords: &Vec<glm::Vec3>, virtual_camera: &mut VirtualCamera, projector_res: Resolution) -> Vec<glm::Vec2> {
     let trans = look_at(virtual_camera.position, virtual_camera.look_at.unwrap(), virtual_camera.up_dir);
     let mut max_rad = -1_f32;
@@ -257,10 +609,24 @@ fn locate_chessboard_corners(photo: &Mat, warp_res: Resolution) -> opencv::Resul
     Ok(point_buffer.iter().map(|pt| vec2(pt.x, pt.y)).collect())
 }
 
-fn take_undistorted_photo(calibration: &camera_calibration::Calibration, camera_type: photo::CameraType) -> opencv::Result<Mat> {
+fn take_undistorted_photo(calibration: &camera_calibration::Calibration, camera: &mut dyn photo::Camera, pattern: &str, recorder: Option<&session::SessionRecorder>) -> opencv::Result<Mat> {
+    let undistorted_img = capture_undistorted(calibration, camera, pattern, recorder)?;
+    imgcodecs::imwrite("alignment-undistorted.jpg", &undistorted_img, &VectorOfi32::new())?;
+
+    // convert to greyscale and invert back to expected color layout and white border
+    // required for the opencv corner detection to work
+    let mut gray = Mat::default()?;
+    let mut inverted_img = Mat::default()?;
+    cvt_color(&undistorted_img, &mut gray, COLOR_BGR2GRAY, 1)?;
+    bitwise_not(&gray, &mut inverted_img, &Mat::default().unwrap())?;
+    imgcodecs::imwrite("alignment-inverted.jpg", &inverted_img, &VectorOfi32::new())?;
+    Ok(inverted_img)
+}
+
+/// Photograph a pattern and remove the lens distortion, keeping the colour
+fn capture_undistorted(calibration: &camera_calibration::Calibration, camera: &mut dyn photo::Camera, pattern: &str, recorder: Option<&session::SessionRecorder>) -> opencv::Result<Mat> {
     // take photo
-    let photo_data = photo::capture_photo(camera_type);
-    let photo = imgcodecs::imdecode(&photo_data, imgcodecs::IMREAD_COLOR)?;
+    let photo = camera.capture_pattern(pattern).expect("failed to capture photo");
 
     // check dimentions match calibration data
     if photo.rows() != calibration.image_height || photo.cols() != calibration.image_width {
@@ -271,38 +637,62 @@ fn take_undistorted_photo(calibration: &camera_calibration::Calibration, camera_
     }
 
     let mut undistorted_img = Mat::default()?;
-    undistort(&photo, &mut undistorted_img, &calibration.camera_matrix, &calibration.distortion_coefficients, &calibration.camera_matrix)?;
-    imgcodecs::imwrite("alignment-undistorted.jpg", &undistorted_img, &VectorOfi32::new())?;
-
-    // convert to greyscale and invert back to expected color layout and white border
-    // required for the opencv corner detection to work
-    let mut gray = Mat::default()?;
-    let mut inverted_img = Mat::default()?;
-    cvt_color(&undistorted_img, &mut gray, COLOR_BGR2GRAY, 1)?;
-    bitwise_not(&gray, &mut inverted_img, &Mat::default().unwrap())?;
-    imgcodecs::imwrite("alignment-inverted.jpg", &inverted_img, &VectorOfi32::new())?;
-    Ok(inverted_img)
+    match calibration.distortion_model {
+        camera_calibration::DistortionModel::RadialTangential => {
+            undistort(&photo, &mut undistorted_img, &calibration.camera_matrix, &calibration.distortion_coefficients, &calibration.camera_matrix)?;
+        }
+        camera_calibration::DistortionModel::Fisheye => {
+            fisheye_undistort_image(&photo, &mut undistorted_img, &calibration.camera_matrix, &calibration.distortion_coefficients, &calibration.camera_matrix, photo.size()?)?;
+        }
+    }
+    if let Some(recorder) = recorder {
+        recorder.record_photo(pattern, &photo, Some(&undistorted_img));
+    }
+    Ok(undistorted_img)
 }
 
 
-fn calibration_json_string(scene_coords: &Vec<glm::Vec3>, uv_coords: &Vec<glm::Vec2>, virtual_camera: &VirtualCamera, warp_res: Resolution) -> String {
+fn calibration_json(projector_warp: &ProjectorWarp, alpha: Option<&[f32]>) -> serde_json::Value {
     // Build final "calibration" JSON document
-    let scene: Vec<&[f32; 3]> = scene_coords.iter().map(|p| p.as_array()).collect();
-    let warp: Vec<&[f32; 2]> = uv_coords.iter().map(|p| p.as_array()).collect();
+    let virtual_camera = &projector_warp.virtual_camera;
+    let warp_res = projector_warp.warp_res;
+    let scene: Vec<&[f32; 3]> = projector_warp.scene_coords.iter().map(|p| p.as_array()).collect();
+    let warp: Vec<&[f32; 2]> = projector_warp.uv_coords.iter().map(|p| p.as_array()).collect();
 
     debug!("scene has {} coordinates", scene.len());
     debug!("warp has {} coordinates", warp.len());
 
-    let json = json!({
-        "fov": virtual_camera.fov,
-        "eye": virtual_camera.position.as_array(),
-        "lookAt": virtual_camera.look_at.unwrap().as_array(),
-        "up": virtual_camera.up_dir.as_array(),
-        "warpResX": warp_res.width,
-        "warpResY": warp_res.height,
-        "warp": warp,
-        "scene": scene
-    });
-
-    serde_json::to_string_pretty(&json).unwrap()
+    let mut json = match &projector_warp.domemaster {
+        Some(slice) => {
+            let warp: Vec<&[f32; 2]> = slice.warp.iter().map(|p| p.as_array()).collect();
+            json!({
+                "mode": "domemaster",
+                "region": slice.region,
+                "warpResX": warp_res.width,
+                "warpResY": warp_res.height,
+                "warp": warp,
+                "scene": scene
+            })
+        }
+        None => json!({
+            "fov": virtual_camera.fov,
+            "eye": virtual_camera.position.as_array(),
+            "lookAt": virtual_camera.look_at.unwrap().as_array(),
+            "up": virtual_camera.up_dir.as_array(),
+            "warpResX": warp_res.width,
+            "warpResY": warp_res.height,
+            "warp": warp,
+            "scene": scene
+        })
+    };
+    if let Some(alpha) = alpha {
+        json["alpha"] = json!(alpha);
+    }
+    if let Some(response) = &projector_warp.response {
+        json["photometric"] = serde_json::to_value(response).unwrap();
+    }
+    if let Some(colour) = &projector_warp.colour {
+        json["colour"] = serde_json::to_value(colour).unwrap();
+    }
+    json
 }
\ No newline at end of file
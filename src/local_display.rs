@@ -0,0 +1,143 @@
+
+use opencv::prelude::*;
+use opencv::highgui;
+use opencv::imgcodecs;
+use super::projector::{ProjectorControl, ProjectorError, ProjectorStatus};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use log::{info, debug};
+
+/// Time given to the window system to put a new frame on screen
+const PAINT_DELAY_MS: i32 = 50;
+
+fn cv_err(e: opencv::Error) -> ProjectorError {
+    ProjectorError::Failed(e.to_string())
+}
+
+/// Shows patterns in a borderless full-screen window, for a projector connected to this
+/// machine as an extra monitor. OpenCV can't enumerate monitors, so the window is moved to the
+/// top-left corner of the chosen monitor in desktop coordinates before going full-screen.
+pub struct WindowDisplay {
+    /// one window per monitor, so several projectors can be driven from one machine
+    name: String,
+}
+
+impl WindowDisplay {
+    /// Spec is `x,y` of the monitor's top-left corner, e.g. "1920,0" for a projector to the
+    /// right of a 1920 pixel wide main monitor. Empty for the main monitor.
+    pub fn open(spec: &str) -> Result<WindowDisplay, ProjectorError> {
+        let (x, y) = if spec.is_empty() {
+            (0, 0)
+        } else {
+            let mut parts = spec.splitn(2, ',').map(|p| p.trim().parse::<i32>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(x)), Some(Ok(y))) => (x, y),
+                _ => return Err(ProjectorError::Failed(format!("monitor position should look like \"1920,0\", not {}", spec)))
+            }
+        };
+        let name = format!("aligner {},{}", x, y);
+        highgui::named_window(&name, highgui::WINDOW_NORMAL).map_err(cv_err)?;
+        highgui::move_window(&name, x, y).map_err(cv_err)?;
+        highgui::set_window_property(&name, highgui::WND_PROP_FULLSCREEN, highgui::WINDOW_FULLSCREEN as f64).map_err(cv_err)?;
+        info!("showing patterns full-screen on the monitor at {},{}", x, y);
+        Ok(WindowDisplay { name: name })
+    }
+}
+
+impl ProjectorControl for WindowDisplay {
+    fn show_image(&mut self, image_bytes: &[u8], _format: &str) -> Result<(), ProjectorError> {
+        let image = imgcodecs::imdecode(&Mat::from_slice(image_bytes).map_err(cv_err)?, imgcodecs::IMREAD_COLOR).map_err(cv_err)?;
+        if image.empty().map_err(cv_err)? {
+            return Err(ProjectorError::Failed(String::from("couldn't decode pattern image")));
+        }
+        // a normal window stretches the image to fill the screen
+        highgui::imshow(&self.name, &image).map_err(cv_err)?;
+        highgui::wait_key(PAINT_DELAY_MS).map_err(cv_err)?;
+        Ok(())
+    }
+
+    fn push_calibration(&mut self, _json: &str) -> Result<(), ProjectorError> {
+        Err(ProjectorError::Unsupported("receiving a calibration"))
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        Ok(ProjectorStatus { online: true, message: Some(String::from("local full-screen window")), ..ProjectorStatus::default() })
+    }
+
+    fn wait_until_displayed(&mut self, _timeout: Duration) -> Result<(), ProjectorError> {
+        // keep the event loop turning so the frame is painted
+        highgui::wait_key(PAINT_DELAY_MS).map_err(cv_err)?;
+        Ok(())
+    }
+}
+
+impl Drop for WindowDisplay {
+    fn drop(&mut self) {
+        highgui::destroy_window(&self.name).ok();
+    }
+}
+
+/// Writes every frame that would be shown to a directory as `frame-001.png` etc., for running
+/// without a screen
+pub struct HeadlessDisplay {
+    dir: PathBuf,
+    frames: usize,
+}
+
+impl HeadlessDisplay {
+    pub fn open(dir: &str) -> Result<HeadlessDisplay, ProjectorError> {
+        let dir = if dir.is_empty() { "." } else { dir };
+        fs::create_dir_all(dir).map_err(|e| ProjectorError::Failed(format!("failed to create {}: {}", dir, e)))?;
+        Ok(HeadlessDisplay { dir: PathBuf::from(dir), frames: 0 })
+    }
+}
+
+impl ProjectorControl for HeadlessDisplay {
+    fn show_image(&mut self, image_bytes: &[u8], format: &str) -> Result<(), ProjectorError> {
+        self.frames += 1;
+        let target = self.dir.join(format!("frame-{:03}.{}", self.frames, format.trim_start_matches('.')));
+        debug!("writing frame to {}", target.display());
+        fs::write(&target, image_bytes).map_err(|e| ProjectorError::Failed(format!("failed to write {}: {}", target.display(), e)))
+    }
+
+    fn push_calibration(&mut self, json: &str) -> Result<(), ProjectorError> {
+        let target = self.dir.join("calibration.json");
+        fs::write(&target, json).map_err(|e| ProjectorError::Failed(format!("failed to write {}: {}", target.display(), e)))
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        Ok(ProjectorStatus {
+            online: true,
+            displayed_frame: if self.frames > 0 { Some(self.frames as u64) } else { None },
+            message: Some(format!("writing frames to {}", self.dir.display())),
+        })
+    }
+
+    fn wait_until_displayed(&mut self, _timeout: Duration) -> Result<(), ProjectorError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headless_writes_numbered_frames_and_calibration() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("frames");
+        let mut display = HeadlessDisplay::open(out.to_str().unwrap()).unwrap();
+        assert_eq!(display.status().unwrap().displayed_frame, None);
+
+        display.show_image(b"first", ".png").unwrap();
+        display.show_image(b"second", "jpg").unwrap();
+        display.wait_until_displayed(Duration::from_secs(1)).unwrap();
+        display.push_calibration("{}").unwrap();
+
+        assert_eq!(fs::read(out.join("frame-001.png")).unwrap(), b"first");
+        assert_eq!(fs::read(out.join("frame-002.jpg")).unwrap(), b"second");
+        assert_eq!(fs::read_to_string(out.join("calibration.json")).unwrap(), "{}");
+        assert_eq!(display.status().unwrap().displayed_frame, Some(2));
+    }
+}
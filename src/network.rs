@@ -1,27 +1,227 @@
-use reqwest::blocking::Response;
-
-/// format should be "png", "jpg" etc
-pub fn post_image(
-    control_url: &str,
-    image_bytes: &[u8],
-    format: &str,
-) -> reqwest::Result<Response> {
-    let url = format!("{}/show_image", control_url);
-    let ctype = format!("image/{}", format);
-    let client = reqwest::blocking::Client::new();
-    client
-        .post(&url)
-        .body(image_bytes.to_vec())
-        .header("Content-Type", &ctype)
-        .send() //.expect("Failed to post image to projector");
-}
-
-/// Send a command and optional json body to the remote control URL
-pub fn send_command(url: &str, command: &str, json_str: &str) {
-    let url = format!("{}/{}", url, command);
-    let req = reqwest::blocking::Client::new()
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .body(String::from(json_str));
-    req.send().expect("failed to send comand to projector");
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use serde::Deserialize;
+use std::fmt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use log::{warn, debug};
+
+#[derive(Debug)]
+pub enum NetworkError {
+    /// couldn't connect, or the connection failed part way
+    Transport(String),
+    /// the server answered with a non-2xx status, body is whatever it said about it
+    Status { status: u16, body: String },
+    Timeout,
+    /// the server's reply wasn't what the protocol expects
+    InvalidReply(String),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Transport(reason) => write!(f, "{}", reason),
+            NetworkError::Status { status, body } if body.is_empty() => write!(f, "server returned {}", status),
+            NetworkError::Status { status, body } => write!(f, "server returned {}: {}", status, body),
+            NetworkError::Timeout => write!(f, "timed out"),
+            NetworkError::InvalidReply(reason) => write!(f, "unexpected reply: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<reqwest::Error> for NetworkError {
+    fn from(e: reqwest::Error) -> NetworkError {
+        if e.is_timeout() {
+            NetworkError::Timeout
+        } else {
+            NetworkError::Transport(e.to_string())
+        }
+    }
+}
+
+impl NetworkError {
+    /// Server errors and dropped connections may go away, a rejected request won't
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NetworkError::Transport(_) | NetworkError::Timeout => true,
+            NetworkError::Status { status, .. } => *status >= 500,
+            NetworkError::InvalidReply(_) => false,
+        }
+    }
+}
+
+/// How requests to the control server are made
+#[derive(Clone, Debug)]
+pub struct NetworkOptions {
+    /// limit for each request, including reading the reply
+    pub timeout: Duration,
+    /// how many times to try again after a transport error or 5xx reply
+    pub retries: u32,
+    /// wait before the first retry, doubled for each one after that
+    pub retry_delay: Duration,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> NetworkOptions {
+        NetworkOptions { timeout: Duration::from_secs(10), retries: 2, retry_delay: Duration::from_millis(500) }
+    }
+}
+
+/// Client for a control server speaking the `/show_image`, `/<command>` and `/displayed`
+/// protocol
+pub struct ControlClient {
+    control_url: String,
+    client: Client,
+    options: NetworkOptions,
+}
+
+impl ControlClient {
+    pub fn new(control_url: &str, options: &NetworkOptions) -> Result<ControlClient, NetworkError> {
+        let client = Client::builder().timeout(options.timeout).build()?;
+        Ok(ControlClient { control_url: control_url.trim_end_matches('/').to_string(), client: client, options: options.clone() })
+    }
+
+    /// Send a request, retrying with backoff on transient failures. Non-2xx replies are
+    /// returned as errors carrying the server's explanation.
+    fn send<F: Fn(&Client) -> RequestBuilder>(&self, url: &str, build: F) -> Result<Response, NetworkError> {
+        let mut delay = self.options.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let result = build(&self.client).send().map_err(NetworkError::from).and_then(check_status);
+            match result {
+                Err(e) if e.is_transient() && attempt < self.options.retries => {
+                    attempt += 1;
+                    warn!("request to {} failed: {}, retrying in {:?} ({} of {})", url, e, delay, attempt, self.options.retries);
+                    sleep(delay);
+                    delay *= 2;
+                }
+                result => return result
+            }
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.control_url, path)
+    }
+
+    /// format should be "png", "jpg" etc
+    pub fn post_image(&self, image_bytes: &[u8], format: &str) -> Result<Response, NetworkError> {
+        let url = self.url("show_image");
+        let ctype = format!("image/{}", format);
+        self.send(&url, |client| client.post(&url).body(image_bytes.to_vec()).header("Content-Type", &ctype))
+    }
+
+    /// Send a command and optional json body to the remote control URL
+    pub fn send_command(&self, command: &str, json_str: &str) -> Result<Response, NetworkError> {
+        let url = self.url(command);
+        self.send(&url, |client| client.post(&url).header("Content-Type", "application/json").body(String::from(json_str)))
+    }
+
+    /// GET a path below the remote control URL
+    pub fn get(&self, path: &str) -> Result<Response, NetworkError> {
+        let url = self.url(path);
+        self.send(&url, |client| client.get(&url))
+    }
+
+    /// Poll `GET /displayed[?frame=<number>]` until the control server replies
+    /// `{"displayed": true}`, confirming the last posted image is actually on screen.
+    /// Returns how long the confirmation took.
+    pub fn wait_until_displayed(&self, frame: Option<u64>, timeout: Duration) -> Result<Duration, NetworkError> {
+        let path = match frame {
+            Some(frame) => format!("displayed?frame={}", frame),
+            None => String::from("displayed")
+        };
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let reply = self.get(&path)?
+                .json::<DisplayedReply>()
+                .map_err(|_| NetworkError::InvalidReply(String::from("projector didn't reply to /displayed with {\"displayed\": bool}")))?;
+            if reply.displayed {
+                debug!("projector confirmed display after {:?}", start.elapsed());
+                return Ok(start.elapsed());
+            }
+            sleep(Duration::from_millis(20));
+        }
+        Err(NetworkError::Timeout)
+    }
+}
+
+/// Turn a non-2xx reply into an error, keeping the body so operators can see why
+pub fn check_status(response: Response) -> Result<Response, NetworkError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().unwrap_or_default();
+    Err(NetworkError::Status { status: status.as_u16(), body: body.trim().to_string() })
+}
+
+#[derive(Deserialize)]
+struct ShowImageReply {
+    frame: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct DisplayedReply {
+    displayed: bool,
+}
+
+/// Frame number the control server assigned to a posted image, if it replied with
+/// `{"frame": <number>}`
+pub fn frame_id(response: Response) -> Option<u64> {
+    response.json::<ShowImageReply>().ok().and_then(|reply| reply.frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_server::StubServer;
+
+    fn client(url: &str) -> ControlClient {
+        let options = NetworkOptions { timeout: Duration::from_secs(5), retries: 2, retry_delay: Duration::from_millis(1) };
+        ControlClient::new(url, &options).unwrap()
+    }
+
+    #[test]
+    fn posts_images_and_commands() {
+        let stub = StubServer::bind("127.0.0.1:0", None).unwrap().spawn();
+        let client = client(stub.url());
+        assert_eq!(frame_id(client.post_image(b"first", "png").unwrap()), Some(1));
+        assert_eq!(frame_id(client.post_image(b"second", "jpeg").unwrap()), Some(2));
+        client.send_command("set_calibration", r#"{"warps": []}"#).unwrap();
+        client.send_command("clear", "").unwrap();
+        client.wait_until_displayed(Some(2), Duration::from_secs(1)).unwrap();
+
+        let state = stub.state();
+        assert_eq!(state.frame, 2);
+        assert_eq!(state.pattern.as_deref(), Some(&b"second"[..]));
+        assert_eq!(state.pattern_format.as_deref(), Some("jpeg"));
+        assert_eq!(state.calibration.as_deref(), Some(r#"{"warps": []}"#));
+        let commands: Vec<&str> = state.commands.iter().map(|(c, _)| c.as_str()).collect();
+        assert_eq!(commands, vec!["set_calibration", "clear"]);
+    }
+
+    #[test]
+    fn rejected_requests_keep_the_reason_and_arent_retried() {
+        let stub = StubServer::bind("127.0.0.1:0", None).unwrap().spawn();
+        let client = client(stub.url());
+        match client.send_command("set_calibration", "not json") {
+            Err(NetworkError::Status { status: 400, body }) => assert!(body.contains("isn't valid JSON"), "{}", body),
+            other => panic!("expected a 400, got {:?}", other.map(|_| ()))
+        }
+        assert!(stub.state().calibration.is_none());
+        match client.get("calibration") {
+            Err(e @ NetworkError::Status { status: 404, .. }) => assert!(!e.is_transient()),
+            other => panic!("expected a 404, got {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn connection_failures_are_transient() {
+        // nothing listens on a port that was just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let error = client(&format!("http://127.0.0.1:{}", port)).get("status").unwrap_err();
+        assert!(error.is_transient(), "{}", error);
+    }
 }
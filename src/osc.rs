@@ -0,0 +1,329 @@
+
+use super::projector::{ProjectorControl, ProjectorError, ProjectorStatus};
+use reqwest::Url;
+use serde_json::Value;
+use std::fs;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use log::{info, debug};
+
+/// Argument of an OSC message
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    /// 64 bit integer, type tag `h`
+    Long(i64),
+    Float(f32),
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+/// OSC strings and blobs are padded with zeros to a multiple of four bytes
+fn pad(buffer: &mut Vec<u8>) {
+    while buffer.len() % 4 != 0 {
+        buffer.push(0);
+    }
+}
+
+fn push_str(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(s.as_bytes());
+    buffer.push(0);
+    pad(buffer);
+}
+
+/// Encode a single OSC 1.0 message
+pub fn encode_message(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut buffer = vec![];
+    push_str(&mut buffer, address);
+    let tags: String = std::iter::once(',').chain(args.iter().map(|arg| match arg {
+        OscArg::Int(_) => 'i',
+        OscArg::Long(_) => 'h',
+        OscArg::Float(_) => 'f',
+        OscArg::Str(_) => 's',
+        OscArg::Blob(_) => 'b',
+    })).collect();
+    push_str(&mut buffer, &tags);
+    for arg in args.iter() {
+        match arg {
+            OscArg::Int(i) => buffer.extend_from_slice(&i.to_be_bytes()),
+            OscArg::Long(i) => buffer.extend_from_slice(&i.to_be_bytes()),
+            OscArg::Float(f) => buffer.extend_from_slice(&f.to_be_bytes()),
+            OscArg::Str(s) => push_str(&mut buffer, s),
+            OscArg::Blob(bytes) => {
+                buffer.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+                buffer.extend_from_slice(bytes);
+                pad(&mut buffer);
+            }
+        }
+    }
+    buffer
+}
+
+/// Largest UDP payload over IPv4
+const MAX_DATAGRAM: usize = 65507;
+
+/// How a pattern is identified to the media server
+#[derive(Clone, Debug, PartialEq)]
+enum PatternMode {
+    /// patterns are preloaded on the server and triggered by number, counting from 1 in the
+    /// order they're shown
+    Index,
+    /// patterns are written to a shared drive and the server is sent the path
+    Path { local_dir: PathBuf, remote_dir: String },
+}
+
+/// Drives a media server or show controller with OSC messages over UDP. Configured with a URL
+/// like `osc://host:port?show=/layer/1/clip/{index}/connect`, query parameters:
+///
+/// - `show`: address to trigger a pattern, `{index}` is replaced by the pattern number
+/// - `clear`: address to blank the output
+/// - `dir`: shared directory to write patterns to, the server is sent their path instead of a
+///   number. `remote_dir` is the same directory as the server sees it, if that differs.
+/// - `calibration`: address for each calibration field, `{field}` is replaced by the field
+///   name (`fov`, `eye`, `lookAt`, `warp` ...). Arrays are sent flattened as floats.
+/// - `blob=1`: send the whole calibration JSON as a blob to the `calibration` address instead
+///
+/// Each message has to fit in one UDP datagram. A calibration with a message that doesn't is
+/// refused before anything is sent. OSC is one-way, so there's no status or display
+/// confirmation.
+pub struct OscProjector {
+    socket: UdpSocket,
+    target: String,
+    show_address: String,
+    clear_address: String,
+    calibration_address: String,
+    calibration_blob: bool,
+    mode: PatternMode,
+    shown: usize,
+}
+
+impl OscProjector {
+    pub fn open(url: &str) -> Result<OscProjector, ProjectorError> {
+        let parsed = Url::parse(url).map_err(|e| ProjectorError::Failed(format!("invalid OSC URL {}: {}", url, e)))?;
+        let host = parsed.host_str().ok_or_else(|| ProjectorError::Failed(format!("OSC URL {} has no host", url)))?;
+        let port = parsed.port().ok_or_else(|| ProjectorError::Failed(format!("OSC URL {} has no port", url)))?;
+        let param = |name: &str| parsed.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned());
+
+        let mode = match param("dir") {
+            Some(dir) => {
+                fs::create_dir_all(&dir).map_err(|e| ProjectorError::Failed(format!("failed to create {}: {}", dir, e)))?;
+                let remote_dir = param("remote_dir").unwrap_or_else(|| dir.clone());
+                PatternMode::Path { local_dir: PathBuf::from(dir), remote_dir: remote_dir }
+            }
+            None => PatternMode::Index
+        };
+
+        let target = format!("{}:{}", host, port);
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| ProjectorError::Failed(e.to_string()))?;
+        socket.connect(&target).map_err(|e| ProjectorError::Failed(format!("can't reach {}: {}", target, e)))?;
+        info!("sending OSC to {}", target);
+
+        Ok(OscProjector {
+            socket: socket,
+            target: target,
+            show_address: param("show").unwrap_or_else(|| String::from("/aligner/show")),
+            clear_address: param("clear").unwrap_or_else(|| String::from("/aligner/clear")),
+            calibration_address: param("calibration").unwrap_or_else(|| String::from("/aligner/calibration/{field}")),
+            calibration_blob: param("blob").map(|b| b == "1" || b == "true").unwrap_or(false),
+            mode: mode,
+            shown: 0,
+        })
+    }
+
+    fn send(&self, address: &str, args: &[OscArg]) -> Result<(), ProjectorError> {
+        debug!("OSC {} {:?}", address, args);
+        self.send_message(&encode_message(address, args))
+    }
+
+    fn send_message(&self, message: &[u8]) -> Result<(), ProjectorError> {
+        self.socket.send(message)
+            .map(|_| ())
+            .map_err(|e| ProjectorError::Failed(format!("failed to send OSC to {}: {}", self.target, e)))
+    }
+}
+
+impl ProjectorControl for OscProjector {
+    fn show_image(&mut self, image_bytes: &[u8], format: &str) -> Result<(), ProjectorError> {
+        self.shown += 1;
+        let address = self.show_address.replace("{index}", &self.shown.to_string());
+        match &self.mode {
+            PatternMode::Index => self.send(&address, &[OscArg::Int(self.shown as i32)]),
+            PatternMode::Path { local_dir, remote_dir } => {
+                let file = format!("pattern-{:03}.{}", self.shown, format.trim_start_matches('.'));
+                let local = local_dir.join(&file);
+                fs::write(&local, image_bytes).map_err(|e| ProjectorError::Failed(format!("failed to write {}: {}", local.display(), e)))?;
+                let remote = format!("{}/{}", remote_dir.trim_end_matches('/'), file);
+                self.send(&address, &[OscArg::Str(remote)])
+            }
+        }
+    }
+
+    fn clear(&mut self) -> Result<(), ProjectorError> {
+        self.send(&self.clear_address, &[])
+    }
+
+    fn push_calibration(&mut self, json: &str) -> Result<(), ProjectorError> {
+        if self.calibration_blob {
+            let address = self.calibration_address.replace("/{field}", "").replace("{field}", "");
+            let message = encode_message(&address, &[OscArg::Blob(json.as_bytes().to_vec())]);
+            if message.len() > MAX_DATAGRAM {
+                return Err(ProjectorError::Failed(format!(
+                    "calibration is {} bytes, too big for one OSC blob, leave out blob=1 to send it field by field", json.len())));
+            }
+            return self.send_message(&message);
+        }
+        let calibration: Value = serde_json::from_str(json).map_err(|e| ProjectorError::Failed(format!("invalid calibration JSON: {}", e)))?;
+        let fields = calibration.as_object().ok_or_else(|| ProjectorError::Failed(String::from("calibration JSON isn't an object")))?;
+        // check every field fits before sending any, so the server isn't left half updated
+        let mut messages = vec![];
+        for (field, value) in fields.iter() {
+            let mut args = vec![];
+            flatten(value, &mut args);
+            let address = self.calibration_address.replace("{field}", field);
+            debug!("OSC {} {:?}", address, args);
+            let message = encode_message(&address, &args);
+            if message.len() > MAX_DATAGRAM {
+                return Err(ProjectorError::Failed(format!("calibration field {} is {} bytes in OSC, too big for one UDP datagram", field, message.len())));
+            }
+            messages.push(message);
+        }
+        for message in messages.iter() {
+            self.send_message(message)?;
+        }
+        Ok(())
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        Err(ProjectorError::Unsupported("status queries over OSC"))
+    }
+}
+
+/// Turn a JSON value into OSC arguments, integers stay integers (64 bit if they don't fit in
+/// 32) and arrays are flattened
+fn flatten(value: &Value, args: &mut Vec<OscArg>) {
+    match value {
+        Value::Number(n) if n.is_i64() => {
+            let i = n.as_i64().unwrap();
+            args.push(if i as i32 as i64 == i { OscArg::Int(i as i32) } else { OscArg::Long(i) })
+        }
+        Value::Number(n) => args.push(OscArg::Float(n.as_f64().unwrap_or(0.) as f32)),
+        Value::String(s) => args.push(OscArg::Str(s.clone())),
+        Value::Bool(b) => args.push(OscArg::Int(*b as i32)),
+        Value::Array(items) => for item in items.iter() { flatten(item, args) },
+        Value::Object(_) => args.push(OscArg::Str(value.to_string())),
+        Value::Null => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pads_strings_and_blobs_to_four_bytes() {
+        let message = encode_message("/ab", &[OscArg::Int(-2), OscArg::Str(String::from("abcd")), OscArg::Float(0.5), OscArg::Blob(vec![1, 2, 3, 4, 5])]);
+        let mut expected = b"/ab\0,isfb\0\0\0".to_vec();
+        expected.extend_from_slice(&[0xff, 0xff, 0xff, 0xfe]);
+        // a string that fills its four bytes still needs a terminating zero
+        expected.extend_from_slice(b"abcd\0\0\0\0");
+        expected.extend_from_slice(&[0x3f, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 0, 0, 5, 1, 2, 3, 4, 5, 0, 0, 0]);
+        assert_eq!(message, expected);
+
+        // no arguments is still a type tag string
+        assert_eq!(encode_message("/clear", &[]), b"/clear\0\0,\0\0\0".to_vec());
+    }
+
+    /// Read a zero terminated, padded OSC string starting at `pos`, moving `pos` past it
+    fn read_str(message: &[u8], pos: &mut usize) -> String {
+        let end = *pos + message[*pos..].iter().position(|&b| b == 0).unwrap();
+        let s = String::from_utf8(message[*pos..end].to_vec()).unwrap();
+        *pos = (end + 4) / 4 * 4;
+        assert!(message[end..*pos].iter().all(|&b| b == 0), "padding isn't zeros");
+        s
+    }
+
+    /// Address and type tags of the next message the listener receives
+    fn receive(listener: &UdpSocket) -> (String, String, Vec<u8>) {
+        let mut buffer = [0_u8; 2048];
+        let n = listener.recv(&mut buffer).expect("no OSC message received");
+        assert_eq!(n % 4, 0, "message isn't a multiple of four bytes");
+        let mut pos = 0;
+        let address = read_str(&buffer[..n], &mut pos);
+        let tags = read_str(&buffer[..n], &mut pos);
+        (address, tags, buffer[pos..n].to_vec())
+    }
+
+    fn listener() -> UdpSocket {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        listener
+    }
+
+    #[test]
+    fn sends_patterns_and_calibration_fields() {
+        let listener = listener();
+        let url = format!("osc://{}?show=/layer/1/clip/{{index}}/connect&calibration=/cal/{{field}}", listener.local_addr().unwrap());
+        let mut projector = OscProjector::open(&url).unwrap();
+
+        projector.show_image(b"png data", "png").unwrap();
+        projector.show_image(b"png data", "png").unwrap();
+        assert_eq!(receive(&listener), (String::from("/layer/1/clip/1/connect"), String::from(",i"), vec![0, 0, 0, 1]));
+        assert_eq!(receive(&listener), (String::from("/layer/1/clip/2/connect"), String::from(",i"), vec![0, 0, 0, 2]));
+        projector.clear().unwrap();
+        assert_eq!(receive(&listener), (String::from("/aligner/clear"), String::from(","), vec![]));
+
+        projector.push_calibration(r#"{"eye": [0.5, 1.5, 2.5], "mode": "perspective", "warpResX": 9}"#).unwrap();
+        let (address, tags, args) = receive(&listener);
+        assert_eq!((address.as_str(), tags.as_str()), ("/cal/eye", ",fff"));
+        assert_eq!(args, [0.5_f32, 1.5, 2.5].iter().flat_map(|f| f.to_be_bytes().to_vec()).collect::<Vec<u8>>());
+        let (address, tags, args) = receive(&listener);
+        assert_eq!((address.as_str(), tags.as_str()), ("/cal/mode", ",s"));
+        assert_eq!(args, b"perspective\0".to_vec());
+        let (address, tags, args) = receive(&listener);
+        assert_eq!((address.as_str(), tags.as_str(), args), ("/cal/warpResX", ",i", vec![0, 0, 0, 9]));
+    }
+
+    #[test]
+    fn sends_calibration_as_a_blob() {
+        let listener = listener();
+        let mut projector = OscProjector::open(&format!("osc://{}?blob=1", listener.local_addr().unwrap())).unwrap();
+        let json = r#"{"fov": 45}"#;
+        projector.push_calibration(json).unwrap();
+        let (address, tags, args) = receive(&listener);
+        assert_eq!((address.as_str(), tags.as_str()), ("/aligner/calibration", ",b"));
+        assert_eq!(&args[..4], &(json.len() as i32).to_be_bytes()[..]);
+        assert_eq!(&args[4..4 + json.len()], json.as_bytes());
+        assert_eq!(args.len(), 4 + 12);
+    }
+
+    #[test]
+    fn keeps_large_integers_whole() {
+        let mut args = vec![];
+        flatten(&serde_json::json!([7, 5_000_000_000_i64, -3]), &mut args);
+        assert_eq!(args, vec![OscArg::Int(7), OscArg::Long(5_000_000_000), OscArg::Int(-3)]);
+        let message = encode_message("/n", &args[1..2]);
+        assert_eq!(message[4..8].to_vec(), b",h\0\0".to_vec());
+        assert_eq!(message[8..].to_vec(), 5_000_000_000_i64.to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn refuses_calibration_too_big_for_a_datagram() {
+        let listener = listener();
+        listener.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let warp: Vec<f32> = (0..20000).map(|i| i as f32 * 0.001).collect();
+        let json = serde_json::json!({"fov": 45, "warp": warp}).to_string();
+
+        let mut blob = OscProjector::open(&format!("osc://{}?blob=1", listener.local_addr().unwrap())).unwrap();
+        let err = blob.push_calibration(&json).unwrap_err().to_string();
+        assert!(err.contains("field by field"), "{}", err);
+
+        let mut fields = OscProjector::open(&format!("osc://{}", listener.local_addr().unwrap())).unwrap();
+        let err = fields.push_calibration(&json).unwrap_err().to_string();
+        assert!(err.contains("field warp"), "{}", err);
+
+        // nothing was sent, not even the fields that fit
+        assert!(listener.recv(&mut [0_u8; 16]).is_err());
+    }
+}
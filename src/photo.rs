@@ -1,91 +1,352 @@
 
 use opencv::prelude::*;
 use opencv::highgui;
-use log::{warn, info, error, debug};
-use std::fs::{File};
-use std::io::{Read, ErrorKind};
-use tempfile::NamedTempFile;
-use std::{thread::sleep, process::{exit, Command}};
+use opencv::imgcodecs;
+use log::{warn, info};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use super::tether;
+use super::video_capture::VideoCaptureCamera;
+use super::http_camera::{HttpCamera, HttpCameraOptions};
 
-pub enum CameraType {
-    TetheredCamera,
-    RemoteHttpCamera{url: String},
-    SingleImageFile{path: String}
+#[derive(Debug)]
+pub enum PhotoError {
+    /// the camera, or the software to drive it, isn't available
+    NotFound(String),
+    /// the camera reported a failure
+    Camera(String),
+    /// the camera didn't deliver a photo in time
+    Timeout,
+    /// the camera returned something that isn't an image
+    Decode(String),
+    /// the backend can't do what was asked
+    Unsupported(&'static str),
+    Io(io::Error),
 }
 
-/// Acquire a photo
-pub fn capture_photo(camera_type: CameraType) -> Mat {
-    match camera_type {
-        CameraType::TetheredCamera => take_photo(),
-        CameraType::RemoteHttpCamera{url} => fetch_photo_from_url(&url),
-        CameraType::SingleImageFile{path} => load_from_file(&path)
+impl fmt::Display for PhotoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhotoError::NotFound(what) => write!(f, "camera not found: {}", what),
+            PhotoError::Camera(reason) => write!(f, "camera error: {}", reason),
+            PhotoError::Timeout => write!(f, "timed out waiting for the camera to capture a photo"),
+            PhotoError::Decode(reason) => write!(f, "failed to decode photo: {}", reason),
+            PhotoError::Unsupported(what) => write!(f, "camera backend doesn't support {}", what),
+            PhotoError::Io(err) => write!(f, "{}", err),
+        }
     }
 }
 
-/// Just returns the specified image file every time
-fn load_from_file(path: &str) -> Mat {
-    warn!("{} is being provided as a camera photo", &path);
+impl std::error::Error for PhotoError {}
+
+impl From<io::Error> for PhotoError {
+    fn from(err: io::Error) -> PhotoError {
+        PhotoError::Io(err)
+    }
+}
+
+/// Exposure and focus settings for a tethered camera. Values are passed through as the camera
+/// reports them, e.g. iso "200", shutter speed "1/30", aperture "5.6", focus mode "Manual".
+#[derive(Clone, Debug, Default)]
+pub struct CameraSettings {
+    pub iso: Option<String>,
+    pub shutter_speed: Option<String>,
+    pub aperture: Option<String>,
+    pub focus_mode: Option<String>,
+}
+
+impl CameraSettings {
+    /// Settings that have been given, keyed by gphoto2 config name
+    pub fn config_values(&self) -> Vec<(&'static str, &str)> {
+        let mut values = vec![];
+        if let Some(v) = &self.iso { values.push(("iso", v.as_str())); }
+        if let Some(v) = &self.shutter_speed { values.push(("shutterspeed", v.as_str())); }
+        if let Some(v) = &self.aperture { values.push(("aperture", v.as_str())); }
+        if let Some(v) = &self.focus_mode { values.push(("focusmode", v.as_str())); }
+        values
+    }
+}
+
+/// Describes where a camera's photos come from
+#[derive(Clone, Debug)]
+pub struct CameraMetadata {
+    /// name the camera is registered under, e.g. "gphoto2" or "http"
+    pub backend: String,
+    /// device, URL or path the photos come from
+    pub source: String,
+    /// false for cameras returning previously recorded photos, which don't need the
+    /// patterns to actually be displayed
+    pub live: bool,
+}
+
+/// Source of photos for detection. Library users can implement this to drive their own
+/// camera and pass it to `produce_calibration` and `locate_camera`.
+pub trait Camera {
+    /// Take a photo and return it decoded as an 8-bit BGR image
+    fn capture(&mut self) -> Result<Mat, PhotoError>;
+
+    /// Take a photo of the named pattern (e.g. "chessboard"). Cameras replaying a recorded
+    /// session use the name to pick the matching photo.
+    fn capture_pattern(&mut self, _pattern: &str) -> Result<Mat, PhotoError> {
+        self.capture()
+    }
+
+    /// Apply exposure and focus settings to subsequent captures. Backends without adjustable
+    /// settings fail with `Unsupported` unless the settings are empty.
+    fn apply_settings(&mut self, settings: &CameraSettings) -> Result<(), PhotoError> {
+        if !settings.config_values().is_empty() {
+            return Err(PhotoError::Unsupported("exposure and focus settings"));
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> CameraMetadata;
+}
+
+/// Everything needed to open a camera from the command line
+#[derive(Clone, Debug, Default)]
+pub struct CameraOptions {
+    pub settings: CameraSettings,
+    pub http: HttpCameraOptions,
+}
+
+/// Builds a camera from the `--camera` argument
+pub type CameraConstructor = fn(spec: &str, options: &CameraOptions) -> Result<Box<dyn Camera>, PhotoError>;
+
+/// Camera backends by name. A `--camera` argument of `<name>:<rest>` (or just `<name>`) picks
+/// the backend registered under that name, anything else is treated as a path to an image
+/// file or a directory of images. No argument means a USB tethered camera.
+pub struct CameraRegistry {
+    backends: Vec<(&'static str, CameraConstructor)>,
+}
+
+impl Default for CameraRegistry {
+    fn default() -> CameraRegistry {
+        let mut registry = CameraRegistry { backends: vec![] };
+        registry.register("gphoto2", |_, _| Ok(Box::new(Gphoto2Camera::new())));
+        registry.register("http", |spec, options| Ok(Box::new(HttpCamera::new(spec, &options.http)?)));
+        registry.register("https", |spec, options| Ok(Box::new(HttpCamera::new(spec, &options.http)?)));
+        registry.register("file", |spec, _| Ok(Box::new(FileCamera::new(strip_scheme(spec, "file"))?)));
+        registry.register("dir", |spec, _| Ok(Box::new(DirectoryCamera::new(strip_scheme(spec, "dir"))?)));
+        registry.register("video", |spec, _| Ok(Box::new(VideoCaptureCamera::open(spec)?)));
+        registry
+    }
+}
+
+impl CameraRegistry {
+    /// Add a backend, replacing any already registered under the same name
+    pub fn register(&mut self, name: &'static str, constructor: CameraConstructor) {
+        self.backends.retain(|(n, _)| *n != name);
+        self.backends.push((name, constructor));
+    }
+
+    pub fn open(&self, spec: Option<&str>, options: &CameraOptions) -> Result<Box<dyn Camera>, PhotoError> {
+        let spec = spec.unwrap_or("gphoto2");
+        let scheme = spec.split(':').next().unwrap();
+        let mut camera: Box<dyn Camera> = match self.backends.iter().find(|(name, _)| *name == scheme) {
+            Some((_, constructor)) => constructor(spec, options)?,
+            None if Path::new(spec).is_dir() => Box::new(DirectoryCamera::new(spec)?),
+            None => Box::new(FileCamera::new(spec)?)
+        };
+        match camera.apply_settings(&options.settings) {
+            Err(PhotoError::Unsupported(_)) => warn!("{} camera has no adjustable settings, ignoring them", camera.metadata().backend),
+            result => result?
+        }
+        info!("using {} camera ({})", camera.metadata().backend, camera.metadata().source);
+        Ok(camera)
+    }
+}
+
+/// Open a camera from the `--camera` argument using the built-in backends
+pub fn open_camera(spec: Option<&str>, options: &CameraOptions) -> Result<Box<dyn Camera>, PhotoError> {
+    CameraRegistry::default().open(spec, options)
+}
+
+fn strip_scheme<'a>(spec: &'a str, scheme: &str) -> &'a str {
+    spec.strip_prefix(scheme).and_then(|rest| rest.strip_prefix(':')).unwrap_or(spec)
+}
+
+/// Decode the compressed image data returned by a camera
+pub fn decode_photo(data: &Mat) -> Result<Mat, PhotoError> {
+    let decoded = imgcodecs::imdecode(data, imgcodecs::IMREAD_COLOR).map_err(|e| PhotoError::Decode(e.to_string()))?;
+    if decoded.empty().unwrap_or(true) {
+        return Err(PhotoError::Decode(String::from("photo data isn't a recognised image format")));
+    }
+    Ok(decoded)
+}
+
+fn read_file(path: &Path) -> Result<Mat, PhotoError> {
     let mut buffer = Vec::new();
-    let mut file = File::open(path).unwrap();
-    file.read_to_end(&mut buffer).unwrap();
-    Mat::from_slice(buffer.as_slice()).unwrap()
-}
-
-fn fetch_photo_from_url(url: &str) -> Mat {
-    info!("fetching camera photo from {}", &url);
-    let client = reqwest::blocking::Client::new();
-    let res = client.get(url).send().expect("failed to request from remote camera URL");
-    let bytes = res.bytes().expect("response didn't contain image data");
-    Mat::from_slice(&bytes[..]).unwrap()
-}
-
-fn take_photo() -> Mat {
-    // Capturing and downloading the same photo isn't reliable on either the Sony
-    // a5100 or a6000. From looking at the gphoto2 command line app/shell it seems that
-    // some kind of USB event comes from the camera to indicate that the new data is available for
-    // download. For now, I'm just going to use the command line app to capture, wait for,
-    // and downoad the image.
-    /*let mut context = gphoto::Context::new().unwrap();
-    let mut camera = gphoto::Camera::autodetect(&mut context).unwrap();
-    let capture = camera.capture_image(&mut context).unwrap();
-    let mut file = gphoto::FileMedia::create(Path::new(&*capture.basename())).unwrap();
-    camera.download(&mut context, &capture, &mut file).unwrap();
-    */
-
-    // take the second of two photos because of issues with sony cameras
-    let file = NamedTempFile::new().unwrap();
-    let fpath = file.path().to_str().unwrap();
-    for _ in 0..2 {
-        let result = Command::new("gphoto2")
-                .args(&["--capture-image-and-download", "--force-overwrite", "--filename", fpath])
-                .output();
-        match result {
-            Ok(output) => {
-                debug!("gphoto2 stdout: {}", String::from_utf8(output.stdout).unwrap());
-                debug!("gphoto2 stderr: {}", String::from_utf8(output.stderr).unwrap());
-            }
-            Err(err) => {
-                match err.kind() {
-                    ErrorKind::NotFound => {
-                        error!("gphoto2 executable not found. Is the gphoto2 package not installed?");
-                        exit(-1);
-                    },
-                    _ => {
-                        error!("Error while running gphoto2: {},", err.to_string());
-                        exit(-1);
-                    }
-                };
+    let mut file = File::open(path)?;
+    file.read_to_end(&mut buffer)?;
+    decode_photo(&Mat::from_slice(buffer.as_slice()).unwrap())
+}
+
+/// USB tethered camera driven through gphoto2
+pub struct Gphoto2Camera {
+    settings: CameraSettings,
+}
+
+impl Gphoto2Camera {
+    pub fn new() -> Gphoto2Camera {
+        Gphoto2Camera { settings: CameraSettings::default() }
+    }
+}
+
+impl Camera for Gphoto2Camera {
+    fn capture(&mut self) -> Result<Mat, PhotoError> {
+        decode_photo(&take_photo(&self.settings)?)
+    }
+
+    fn apply_settings(&mut self, settings: &CameraSettings) -> Result<(), PhotoError> {
+        // applied on the camera with every capture
+        self.settings = settings.clone();
+        Ok(())
+    }
+
+    fn metadata(&self) -> CameraMetadata {
+        CameraMetadata { backend: String::from("gphoto2"), source: String::from("usb"), live: true }
+    }
+}
+
+/// Just returns the specified image file every time
+pub struct FileCamera {
+    path: PathBuf,
+}
+
+impl FileCamera {
+    pub fn new(path: &str) -> Result<FileCamera, PhotoError> {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            return Err(PhotoError::NotFound(format!("{} is not an image file", path.display())));
+        }
+        Ok(FileCamera { path: path })
+    }
+}
+
+impl Camera for FileCamera {
+    fn capture(&mut self) -> Result<Mat, PhotoError> {
+        warn!("{} is being provided as a camera photo", self.path.display());
+        read_file(&self.path)
+    }
+
+    fn metadata(&self) -> CameraMetadata {
+        CameraMetadata { backend: String::from("file"), source: self.path.display().to_string(), live: false }
+    }
+}
+
+/// Photos of a recorded session, see `DirectoryCamera`
+#[derive(Deserialize, Debug)]
+pub struct SessionManifest {
+    pub photos: Vec<ManifestPhoto>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ManifestPhoto {
+    /// name of the pattern that was displayed, e.g. "chessboard"
+    pub pattern: String,
+    /// photo file, relative to the manifest
+    pub file: String,
+}
+
+/// Name of the manifest file that lets `DirectoryCamera` match photos to patterns
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Replays a directory of photos, e.g. to rerun a recorded calibration session without any
+/// hardware attached. If the directory holds a `manifest.json` listing which pattern each
+/// photo shows, photos are matched to the pattern being captured (in recorded order for
+/// repeated patterns). Otherwise the images are returned one after another in file name order.
+pub struct DirectoryCamera {
+    dir: PathBuf,
+    /// photo files with the pattern they show, if known
+    photos: Vec<(Option<String>, PathBuf)>,
+    used: Vec<bool>,
+}
+
+impl DirectoryCamera {
+    pub fn new(dir: &str) -> Result<DirectoryCamera, PhotoError> {
+        let manifest_path = Path::new(dir).join(MANIFEST_FILE);
+        let photos = if manifest_path.is_file() {
+            let manifest: SessionManifest = serde_json::from_str(&fs::read_to_string(&manifest_path)?)
+                .map_err(|e| PhotoError::Camera(format!("invalid session manifest {}: {}", manifest_path.display(), e)))?;
+            info!("replaying {} photos from {}", manifest.photos.len(), manifest_path.display());
+            manifest.photos.into_iter()
+                .map(|photo| (Some(photo.pattern), Path::new(dir).join(photo.file)))
+                .collect()
+        } else {
+            DirectoryCamera::image_files(dir)?.into_iter().map(|path| (None, path)).collect()
+        };
+        if photos.is_empty() {
+            return Err(PhotoError::NotFound(format!("no images found in {}", dir)));
+        }
+        let used = vec![false; photos.len()];
+        Ok(DirectoryCamera { dir: PathBuf::from(dir), photos: photos, used: used })
+    }
+
+    fn image_files(dir: &str) -> Result<Vec<PathBuf>, PhotoError> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_image = path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+                .unwrap_or(false);
+            if path.is_file() && is_image {
+                files.push(path);
             }
         }
-        
-        sleep(std::time::Duration::from_millis(1000));
+        files.sort();
+        Ok(files)
     }
-        
-    let mut buffer = Vec::new();
-    let mut file = File::open(fpath).unwrap();
-    file.read_to_end(&mut buffer).unwrap();
+
+    fn replay(&mut self, index: usize) -> Result<Mat, PhotoError> {
+        self.used[index] = true;
+        let path = &self.photos[index].1;
+        warn!("{} is being provided as a camera photo", path.display());
+        read_file(path)
+    }
+}
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "tif", "tiff", "bmp"];
+
+impl Camera for DirectoryCamera {
+    fn capture(&mut self) -> Result<Mat, PhotoError> {
+        match self.used.iter().position(|used| !used) {
+            Some(index) => self.replay(index),
+            None => Err(PhotoError::NotFound(format!("all {} images in {} have been used", self.photos.len(), self.dir.display())))
+        }
+    }
+
+    fn capture_pattern(&mut self, pattern: &str) -> Result<Mat, PhotoError> {
+        if self.photos.iter().all(|(p, _)| p.is_none()) {
+            return self.capture();
+        }
+        let next = (0..self.photos.len())
+            .find(|&i| !self.used[i] && self.photos[i].0.as_deref() == Some(pattern));
+        match next {
+            Some(index) => self.replay(index),
+            None => Err(PhotoError::NotFound(format!("no more recorded photos of pattern {} in {}", pattern, self.dir.display())))
+        }
+    }
+
+    fn metadata(&self) -> CameraMetadata {
+        CameraMetadata { backend: String::from("dir"), source: self.dir.display().to_string(), live: false }
+    }
+}
+
+/// Capture through libgphoto2, or the gphoto2 command line app in builds without it. Both
+/// wait for the camera to report the new file.
+fn take_photo(settings: &CameraSettings) -> Result<Mat, PhotoError> {
+    #[cfg(feature = "native-gphoto2")]
+    let mut device = tether::Gphoto2Device::autodetect()?;
+    #[cfg(not(feature = "native-gphoto2"))]
+    let mut device = tether::CommandLineDevice::new()?;
+    let buffer = tether::capture(&mut device, settings, std::time::Duration::from_secs(30))?;
     info!("returning gphoto image data");
-    Mat::from_slice(buffer.as_slice()).unwrap()
+    Ok(Mat::from_slice(buffer.as_slice()).unwrap())
 }
 
 // Show encoded image contained in mat and wait
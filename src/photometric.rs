@@ -0,0 +1,379 @@
+
+use opencv::prelude::*;
+use opencv::core::*;
+use serde::Serialize;
+use log::{info, warn};
+
+/// Photometric capture settings
+#[derive(Clone, Copy, Debug)]
+pub struct PhotometricOptions {
+    /// grey levels per channel, evenly spaced from 0 to 255
+    pub steps: u32,
+    /// also capture white, and work out colour matching and luminance flattening
+    pub colour_matching: bool,
+}
+
+impl Default for PhotometricOptions {
+    fn default() -> PhotometricOptions {
+        PhotometricOptions { steps: 9, colour_matching: false }
+    }
+}
+
+/// Camera RGB at each detected point, None where the point was too close to the photo edge
+pub type PointSamples = Vec<Option<[f32; 3]>>;
+
+/// Per-point captures of the full-level primaries and white, for colour matching
+#[derive(Clone, Debug)]
+pub struct ColourSamples {
+    pub black: PointSamples,
+    /// full red, green and blue
+    pub primaries: [PointSamples; 3],
+    pub white: PointSamples,
+}
+
+/// Measured transfer curve of one projector channel
+#[derive(Serialize, Clone, Debug)]
+pub struct ChannelResponse {
+    /// best fitting power law, output = input ^ gamma
+    pub gamma: f32,
+    /// output at each level, normalized so black is 0 and full level is 1
+    pub response: Vec<f32>,
+    /// camera value for black and for full level
+    pub black: f32,
+    pub white: f32,
+}
+
+/// Measured transfer curves of a projector
+#[derive(Serialize, Clone, Debug)]
+pub struct ProjectorResponse {
+    /// input levels the responses were measured at
+    pub levels: Vec<u8>,
+    pub red: ChannelResponse,
+    pub green: ChannelResponse,
+    pub blue: ChannelResponse,
+    /// camera RGB at each point with the projector showing black
+    #[serde(skip)]
+    pub black_samples: PointSamples,
+    #[serde(skip)]
+    pub colour_samples: Option<ColourSamples>,
+}
+
+/// Levels to measure, always including 0 and 255
+pub fn levels(steps: u32) -> Vec<u8> {
+    let steps = steps.max(2);
+    (0..steps).map(|i| (i as f32 * 255. / (steps - 1) as f32).round() as u8).collect()
+}
+
+/// Half-width of the square averaged around each sample point
+const SAMPLE_RADIUS: i32 = 2;
+
+/// Average RGB in a small square around each point of an undistorted photo
+pub fn sample_rgb(photo: &Mat, points: &[glm::Vec2]) -> opencv::Result<PointSamples> {
+    let (w, h) = (photo.cols(), photo.rows());
+    let mut samples = vec![];
+    for point in points.iter() {
+        let (x, y) = (point.x.round() as i32, point.y.round() as i32);
+        if x < SAMPLE_RADIUS || y < SAMPLE_RADIUS || x >= w - SAMPLE_RADIUS || y >= h - SAMPLE_RADIUS {
+            samples.push(None);
+            continue;
+        }
+        let roi = Mat::roi(photo, Rect::new(x - SAMPLE_RADIUS, y - SAMPLE_RADIUS, SAMPLE_RADIUS * 2 + 1, SAMPLE_RADIUS * 2 + 1))?;
+        let avg = mean(&roi, &Mat::default()?)?;
+        // photos are BGR
+        samples.push(Some([avg[2] as f32, avg[1] as f32, avg[0] as f32]));
+    }
+    Ok(samples)
+}
+
+/// Least squares fit of `y = x ^ gamma` through the origin in log space, ignoring the ends of
+/// the curve where the logarithm isn't defined or is meaningless
+pub fn fit_gamma(levels: &[u8], response: &[f32]) -> f32 {
+    let (mut sxy, mut sxx) = (0_f64, 0_f64);
+    for (&level, &y) in levels.iter().zip(response.iter()) {
+        let x = level as f64 / 255.;
+        if x <= 0. || x >= 1. || y <= 0. {
+            continue;
+        }
+        let (lx, ly) = (x.ln(), (y as f64).ln());
+        sxy += lx * ly;
+        sxx += lx * lx;
+    }
+    if sxx == 0. {
+        warn!("not enough grey levels to fit a gamma, assuming 2.2");
+        return 2.2;
+    }
+    (sxy / sxx) as f32
+}
+
+/// Normalize the camera values measured at each level and fit a gamma
+pub fn channel_response(levels: &[u8], values: &[f32]) -> ChannelResponse {
+    let black = values[0];
+    let white = *values.last().unwrap();
+    let range = (white - black).max(1e-3);
+    let response: Vec<f32> = values.iter().map(|v| (v - black) / range).collect();
+    ChannelResponse { gamma: fit_gamma(levels, &response), response: response, black: black, white: white }
+}
+
+fn channel(samples: &PointSamples, c: usize) -> Vec<f32> {
+    samples.iter().filter_map(|s| s.map(|rgb| rgb[c])).collect()
+}
+
+fn average(values: &[f32]) -> f32 {
+    if values.is_empty() { 0. } else { values.iter().sum::<f32>() / values.len() as f32 }
+}
+
+/// Show grey ramps on each channel and measure the projector's response at the detected
+/// scene points. `capture(pattern name, r, g, b)` shows a solid colour and returns the
+/// undistorted photo of it, linearised with the camera's response curve if there is one.
+pub fn measure<F>(options: PhotometricOptions, points: &[glm::Vec2], mut capture: F) -> opencv::Result<ProjectorResponse>
+    where F: FnMut(&str, u8, u8, u8) -> opencv::Result<Mat>
+{
+    let levels = levels(options.steps);
+
+    // black is the same for every channel
+    let black = sample_rgb(&capture("grey-000", 0, 0, 0)?, points)?;
+    let mut values = [vec![], vec![], vec![]]; // red, green, blue
+    for (c, values) in values.iter_mut().enumerate() {
+        values.push(average(&channel(&black, c)));
+    }
+
+    let mut primaries = [vec![], vec![], vec![]];
+    for (c, name) in ["r", "g", "b"].iter().enumerate() {
+        for &level in levels[1..].iter() {
+            let mut rgb = [0_u8; 3];
+            rgb[c] = level;
+            let samples = sample_rgb(&capture(&format!("grey-{}-{:03}", name, level), rgb[0], rgb[1], rgb[2])?, points)?;
+            values[c].push(average(&channel(&samples, c)));
+            if level == 255 {
+                primaries[c] = samples;
+            }
+        }
+    }
+
+    let colour_samples = if options.colour_matching {
+        let white = sample_rgb(&capture("white", 255, 255, 255)?, points)?;
+        Some(ColourSamples { black: black.clone(), primaries: primaries, white: white })
+    } else {
+        None
+    };
+
+    let response = ProjectorResponse {
+        red: channel_response(&levels, &values[0]),
+        green: channel_response(&levels, &values[1]),
+        blue: channel_response(&levels, &values[2]),
+        levels: levels,
+        black_samples: black,
+        colour_samples: colour_samples,
+    };
+    info!("projector gamma is {:.2} red, {:.2} green, {:.2} blue", response.red.gamma, response.green.gamma, response.blue.gamma);
+    Ok(response)
+}
+
+/// Colour and brightness correction for one projector, applied by the renderer in linear light
+#[derive(Serialize, Clone, Debug)]
+pub struct ColourCorrection {
+    /// row-major 3x3 matrix taking the wanted linear RGB to the RGB to send to this projector
+    pub matrix: [[f32; 3]; 3],
+    /// brightness multiplier at each warp vertex, row-major like the warp
+    pub attenuation: Vec<f32>,
+    pub cols: i32,
+    pub rows: i32,
+}
+
+fn luminance(rgb: [f32; 3]) -> f32 {
+    0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]
+}
+
+fn subtract(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+/// Camera RGB of each full-level primary above black, averaged over the points. Columns are
+/// the primaries.
+fn primary_matrix(samples: &ColourSamples) -> [[f32; 3]; 3] {
+    let mut m = [[0_f32; 3]; 3];
+    for p in 0..3 {
+        let diffs: Vec<[f32; 3]> = samples.primaries[p].iter().zip(samples.black.iter())
+            .filter_map(|(primary, black)| match (primary, black) {
+                (Some(primary), Some(black)) => Some(subtract(*primary, *black)),
+                _ => None
+            })
+            .collect();
+        for c in 0..3 {
+            m[c][p] = average(&diffs.iter().map(|d| d[c]).collect::<Vec<_>>());
+        }
+    }
+    m
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut m = [[0_f32; 3]; 3];
+    for r in 0..3 {
+        for c in 0..3 {
+            m[r][c] = (0..3).map(|k| a[r][k] * b[k][c]).sum();
+        }
+    }
+    m
+}
+
+fn invert(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    let mut inv = [[0_f32; 3]; 3];
+    for r in 0..3 {
+        for c in 0..3 {
+            // cofactor of the transposed element
+            let (r1, r2) = ((c + 1) % 3, (c + 2) % 3);
+            let (c1, c2) = ((r + 1) % 3, (r + 2) % 3);
+            inv[r][c] = (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]) / det;
+        }
+    }
+    Some(inv)
+}
+
+/// Fraction of the dimmest points ignored when choosing the brightness everything is flattened
+/// to, so one bad sample doesn't darken the whole dome
+const FLATTEN_PERCENTILE: f32 = 0.05;
+
+/// Work out colour matching matrices toward the average projector's primaries, and
+/// attenuation maps flattening brightness to the dimmest part of the surface. Matrices are
+/// scaled together so no projector is asked for more than full level.
+pub fn match_projectors(samples: &[&ColourSamples], grids: &[(i32, i32)]) -> Result<Vec<ColourCorrection>, String> {
+    if samples.len() != grids.len() {
+        return Err(format!("{} projectors have colour samples but {} grids were given", samples.len(), grids.len()));
+    }
+    let measured: Vec<[[f32; 3]; 3]> = samples.iter().map(|s| primary_matrix(s)).collect();
+    let mut target = [[0_f32; 3]; 3];
+    for m in measured.iter() {
+        for r in 0..3 {
+            for c in 0..3 {
+                target[r][c] += m[r][c] / measured.len() as f32;
+            }
+        }
+    }
+
+    let mut matrices = vec![];
+    for (i, m) in measured.iter().enumerate() {
+        let inverse = invert(m).ok_or_else(|| format!("primaries of projector {} aren't independent, is it showing colour?", i))?;
+        matrices.push(multiply(&inverse, &target));
+    }
+    // white in must not need more than full level out on any channel, scale down only
+    let scale = matrices.iter()
+        .flat_map(|m| m.iter().map(|row| row.iter().sum::<f32>()))
+        .fold(1_f32, f32::max);
+    for m in matrices.iter_mut() {
+        for row in m.iter_mut() {
+            for v in row.iter_mut() { *v /= scale; }
+        }
+    }
+
+    // brightness of each point relative to its projector's average
+    let relative: Vec<Vec<Option<f32>>> = samples.iter().map(|s| {
+        let white: Vec<Option<f32>> = s.white.iter().zip(s.black.iter())
+            .map(|(white, black)| match (white, black) {
+                (Some(white), Some(black)) => Some(luminance(subtract(*white, *black))),
+                _ => None
+            })
+            .collect();
+        let mean = average(&white.iter().filter_map(|y| *y).collect::<Vec<_>>()).max(1e-6);
+        white.iter().map(|y| y.map(|y| y / mean)).collect()
+    }).collect();
+    let mut all: Vec<f32> = relative.iter().flat_map(|r| r.iter().filter_map(|y| *y)).filter(|y| *y > 0.).collect();
+    if all.is_empty() {
+        return Err(String::from("no white samples to flatten brightness with"));
+    }
+    all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let floor = all[((all.len() - 1) as f32 * FLATTEN_PERCENTILE) as usize];
+    info!("flattening brightness to {:.0}% of the average", floor * 100.);
+
+    Ok(matrices.into_iter().zip(relative.iter()).zip(grids.iter()).map(|((matrix, relative), &(cols, rows))| {
+        ColourCorrection {
+            matrix: matrix,
+            // unmeasured points are left alone
+            attenuation: relative.iter().map(|y| y.map(|y| (floor / y.max(1e-6)).min(1.)).unwrap_or(1.)).collect(),
+            cols: cols,
+            rows: rows,
+        }
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_the_gamma_of_a_power_law() {
+        let levels = levels(9);
+        assert_eq!((levels[0], levels[8]), (0, 255));
+        let black = 12.;
+        let values: Vec<f32> = levels.iter().map(|&l| black + 200. * (l as f32 / 255.).powf(2.2)).collect();
+        let response = channel_response(&levels, &values);
+        assert!((response.gamma - 2.2).abs() < 1e-3, "gamma {}", response.gamma);
+        assert!(response.response[0].abs() < 1e-6 && (response.response[8] - 1.).abs() < 1e-6);
+    }
+
+    /// Samples at four points of a projector whose primaries reach `gain` times a reference
+    fn samples(gain: f32) -> ColourSamples {
+        let black = [10_f32, 12., 8.];
+        let primary = |c: usize| {
+            // a little crosstalk into the other channels
+            let mut rgb = [black[0] + 5. * gain, black[1] + 5. * gain, black[2] + 5. * gain];
+            rgb[c] = black[c] + 100. * gain;
+            Some(rgb)
+        };
+        ColourSamples {
+            black: vec![Some(black); 4],
+            primaries: [vec![primary(0); 4], vec![primary(1); 4], vec![primary(2); 4]],
+            white: vec![Some([black[0] + 110. * gain, black[1] + 110. * gain, black[2] + 110. * gain]); 4],
+        }
+    }
+
+    #[test]
+    fn inverts_matrices() {
+        let m = [[2_f32, 0.5, 0.1], [0.3, 1.5, 0.2], [0.1, 0.4, 3.]];
+        let product = multiply(&invert(&m).unwrap(), &m);
+        for r in 0..3 {
+            for c in 0..3 {
+                let expected = if r == c { 1. } else { 0. };
+                assert!((product[r][c] - expected).abs() < 1e-5, "{:?}", product);
+            }
+        }
+        assert!(invert(&[[1., 2., 3.], [2., 4., 6.], [0., 1., 0.]]).is_none());
+    }
+
+    #[test]
+    fn dims_the_brighter_projector_and_never_scales_up() {
+        let (dim, bright) = (samples(1.), samples(2.));
+        let corrections = match_projectors(&[&dim, &bright], &[(2, 2), (2, 2)]).unwrap();
+        // the dim projector is driven at full level for white, the bright one at half
+        let white = |m: &[[f32; 3]; 3]| [m[0].iter().sum::<f32>(), m[1].iter().sum::<f32>(), m[2].iter().sum::<f32>()];
+        for (correction, level) in corrections.iter().zip([1_f32, 0.5].iter()) {
+            for channel in white(&correction.matrix).iter() {
+                assert!((channel - level).abs() < 1e-4, "{:?}", correction.matrix);
+            }
+            // both projectors are evenly lit
+            assert!(correction.attenuation.iter().all(|a| (a - 1.).abs() < 1e-6));
+        }
+        // and both then give the same colour
+        let light = |samples: &ColourSamples, m: &[[f32; 3]; 3]| multiply(&primary_matrix(samples), m);
+        let (a, b) = (light(&dim, &corrections[0].matrix), light(&bright, &corrections[1].matrix));
+        for r in 0..3 {
+            for c in 0..3 {
+                assert!((a[r][c] - b[r][c]).abs() < 1e-3, "{:?} {:?}", a, b);
+            }
+        }
+
+        // two matching projectors need no correction, rather than being pushed past white
+        let corrections = match_projectors(&[&dim, &dim], &[(2, 2), (2, 2)]).unwrap();
+        for r in 0..3 {
+            for c in 0..3 {
+                let expected = if r == c { 1. } else { 0. };
+                assert!((corrections[0].matrix[r][c] - expected).abs() < 1e-5, "{:?}", corrections[0].matrix);
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+
+use super::images;
+use super::network::{self, ControlClient, NetworkError, NetworkOptions};
+use super::local_display::{WindowDisplay, HeadlessDisplay};
+use super::osc::OscProjector;
+use super::websocket::WebSocketProjector;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::time::Duration;
+use log::{info, debug};
+
+#[derive(Debug)]
+pub enum ProjectorError {
+    /// the projector or media server couldn't be reached or refused the request
+    Failed(String),
+    /// the projector didn't confirm in time
+    Timeout,
+    /// the backend can't do what was asked
+    Unsupported(&'static str),
+    /// a request to a media server failed
+    Network(NetworkError),
+}
+
+impl fmt::Display for ProjectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectorError::Failed(reason) => write!(f, "projector error: {}", reason),
+            ProjectorError::Timeout => write!(f, "timed out waiting for the projector"),
+            ProjectorError::Unsupported(what) => write!(f, "projector backend doesn't support {}", what),
+            ProjectorError::Network(e) => write!(f, "projector request failed: {}", e),
+        }
+    }
+}
+
+impl From<NetworkError> for ProjectorError {
+    fn from(e: NetworkError) -> ProjectorError {
+        match e {
+            NetworkError::Timeout => ProjectorError::Timeout,
+            e => ProjectorError::Network(e)
+        }
+    }
+}
+
+impl std::error::Error for ProjectorError {}
+
+/// What a projector reports about itself
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ProjectorStatus {
+    pub online: bool,
+    /// number of the frame currently on screen, if the backend tracks them
+    #[serde(default)]
+    pub displayed_frame: Option<u64>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// Shows patterns on a projector and hands it the resulting calibration. The HTTP media
+/// server protocol is one implementation, others can drive a projector however they like.
+pub trait ProjectorControl {
+    /// Show an encoded image full-screen. Format is "png", "jpg" etc.
+    fn show_image(&mut self, image_bytes: &[u8], format: &str) -> Result<(), ProjectorError>;
+
+    /// Blank the output
+    fn clear(&mut self) -> Result<(), ProjectorError> {
+        let black = images::pixel_png(0, 0, 0);
+        self.show_image(&black.to_slice(), "png")
+    }
+
+    /// Send calibration JSON for the renderer to apply
+    fn push_calibration(&mut self, json: &str) -> Result<(), ProjectorError>;
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError>;
+
+    /// Block until the last image shown is confirmed to be on screen
+    fn wait_until_displayed(&mut self, _timeout: Duration) -> Result<(), ProjectorError> {
+        Err(ProjectorError::Unsupported("display confirmation"))
+    }
+}
+
+/// Pick a projector backend for the `--control-url` argument: an http(s) media server URL, a
+/// renderer listening on a `ws://` or `wss://` WebSocket, an `osc://host:port` show
+/// controller, `window[:x,y]` for a full-screen window on a monitor of this machine or
+/// `headless[:dir]` to write frames to disk. Without a URL the operator displays patterns by
+/// hand, unless the camera is replaying a recorded session in which case patterns don't need
+/// to be shown at all.
+pub fn open_projector(control_url: Option<&str>, live_camera: bool, network_options: &NetworkOptions) -> Result<Box<dyn ProjectorControl>, ProjectorError> {
+    match control_url {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => Ok(Box::new(HttpProjector::new(url, network_options)?)),
+        Some(url) if url.starts_with("ws://") || url.starts_with("wss://") => Ok(Box::new(WebSocketProjector::connect(url)?)),
+        Some(url) if url.starts_with("osc://") => Ok(Box::new(OscProjector::open(url)?)),
+        Some(url) if url == "window" || url.starts_with("window:") => Ok(Box::new(WindowDisplay::open(url.trim_start_matches("window").trim_start_matches(':'))?)),
+        Some(url) if url == "headless" || url.starts_with("headless:") => Ok(Box::new(HeadlessDisplay::open(url.trim_start_matches("headless").trim_start_matches(':'))?)),
+        Some(url) => Err(ProjectorError::Failed(format!("don't know how to control a projector at {}", url))),
+        None if live_camera => Ok(Box::new(ManualProjector::new())),
+        None => Ok(Box::new(DetachedProjector))
+    }
+}
+
+/// Media server speaking the bespoke HTTP protocol: `POST /show_image` with the image as the
+/// body, `POST /<command>` with a JSON body, `GET /status` and `GET /displayed`
+pub struct HttpProjector {
+    client: ControlClient,
+    /// frame number the server gave the last image, if it reports them
+    last_frame: Option<u64>,
+}
+
+impl HttpProjector {
+    pub fn new(control_url: &str, options: &NetworkOptions) -> Result<HttpProjector, ProjectorError> {
+        Ok(HttpProjector { client: ControlClient::new(control_url, options)?, last_frame: None })
+    }
+}
+
+impl ProjectorControl for HttpProjector {
+    fn show_image(&mut self, image_bytes: &[u8], format: &str) -> Result<(), ProjectorError> {
+        let response = self.client.post_image(image_bytes, format)?;
+        self.last_frame = network::frame_id(response);
+        Ok(())
+    }
+
+    fn push_calibration(&mut self, json: &str) -> Result<(), ProjectorError> {
+        self.client.send_command("set_calibration", json)?;
+        Ok(())
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        match self.client.get("status") {
+            Ok(response) => {
+                let mut status = response.json::<ProjectorStatus>().unwrap_or_default();
+                status.online = true;
+                Ok(status)
+            }
+            // it answered, it just doesn't do status
+            Err(NetworkError::Status { status, body }) => Ok(ProjectorStatus {
+                online: true,
+                message: Some(format!("/status returned {}: {}", status, body)),
+                ..ProjectorStatus::default()
+            }),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn wait_until_displayed(&mut self, timeout: Duration) -> Result<(), ProjectorError> {
+        self.client.wait_until_displayed(self.last_frame, timeout)?;
+        Ok(())
+    }
+}
+
+/// No way to control the projector, so the operator is asked to display each pattern. The
+/// pattern is written to the working directory to make that easier.
+pub struct ManualProjector {
+    shown: usize,
+}
+
+impl ManualProjector {
+    pub fn new() -> ManualProjector {
+        ManualProjector { shown: 0 }
+    }
+}
+
+impl ProjectorControl for ManualProjector {
+    fn show_image(&mut self, image_bytes: &[u8], format: &str) -> Result<(), ProjectorError> {
+        self.shown += 1;
+        let fname = format!("aligner-pattern-{}.{}", self.shown, format);
+        fs::write(&fname, image_bytes).map_err(|e| ProjectorError::Failed(format!("failed to write {}: {}", fname, e)))?;
+        info!("Please display {} full-screen on the projector and press any key", fname);
+        std::io::stdin().bytes().next();
+        info!("Continuing...");
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), ProjectorError> {
+        info!("Please blank the projector and press any key");
+        std::io::stdin().bytes().next();
+        Ok(())
+    }
+
+    fn push_calibration(&mut self, _json: &str) -> Result<(), ProjectorError> {
+        Err(ProjectorError::Unsupported("receiving a calibration"))
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        Ok(ProjectorStatus { online: true, message: Some(String::from("controlled by the operator")), ..ProjectorStatus::default() })
+    }
+}
+
+/// Used when replaying recorded photos, nothing needs to be shown
+pub struct DetachedProjector;
+
+impl ProjectorControl for DetachedProjector {
+    fn show_image(&mut self, _image_bytes: &[u8], _format: &str) -> Result<(), ProjectorError> {
+        debug!("no projector attached, not showing pattern");
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), ProjectorError> {
+        Ok(())
+    }
+
+    fn push_calibration(&mut self, _json: &str) -> Result<(), ProjectorError> {
+        Err(ProjectorError::Unsupported("receiving a calibration"))
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        Ok(ProjectorStatus::default())
+    }
+
+    fn wait_until_displayed(&mut self, _timeout: Duration) -> Result<(), ProjectorError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,722 @@
+
+use glm::*;
+use serde::Serialize;
+use std::f64::consts;
+use log::{info, warn, debug};
+use super::{PhysicalCamera, Resolution};
+use super::blend;
+use super::surfaces::SurfaceType;
+
+/// Joint refinement settings
+#[derive(Clone, Copy, Debug)]
+pub struct RefineOptions {
+    pub iterations: u32,
+    /// how strongly the surface and camera are held where they were measured: the camera
+    /// reprojection error, in pixels, worth moving them by 1% of the surface's distance or
+    /// turning the camera by a degree
+    pub stiffness: f32,
+}
+
+impl Default for RefineOptions {
+    fn default() -> RefineOptions {
+        RefineOptions { iterations: 200, stiffness: 1. }
+    }
+}
+
+/// How far apart overlapping warps put the same point on the surface, in scene units
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct SeamError {
+    pub rms: f32,
+    pub max: f32,
+    /// corners found inside another projector's chessboard
+    pub samples: usize,
+}
+
+/// Corner `corner` of projector `projector` seen by the camera inside projector `other`'s
+/// chessboard, where `weights` of the other's `corners` interpolate to the same camera point
+struct Correspondence {
+    projector: usize,
+    corner: usize,
+    other: usize,
+    corners: [usize; 3],
+    weights: [f32; 3],
+}
+
+/// Find a camera point inside a chessboard's detected corners, as interpolation weights of
+/// three of them
+fn locate_in_grid(image_points: &[glm::Vec2], warp_res: Resolution, point: glm::Vec2) -> Option<([usize; 3], [f32; 3])> {
+    let (cols, rows) = (warp_res.width as usize, warp_res.height as usize);
+    for j in 0..rows - 1 {
+        for i in 0..cols - 1 {
+            let cell = [j * cols + i, j * cols + i + 1, (j + 1) * cols + i + 1, (j + 1) * cols + i];
+            let p: Vec<glm::Vec2> = cell.iter().map(|&k| image_points[k]).collect();
+            let (min_x, max_x) = p.iter().fold((std::f32::MAX, std::f32::MIN), |(lo, hi), v| (lo.min(v.x), hi.max(v.x)));
+            let (min_y, max_y) = p.iter().fold((std::f32::MAX, std::f32::MIN), |(lo, hi), v| (lo.min(v.y), hi.max(v.y)));
+            if point.x < min_x || point.x > max_x || point.y < min_y || point.y > max_y {
+                continue;
+            }
+            for &(a, b, c) in [(0, 1, 2), (0, 2, 3)].iter() {
+                if let Some((wa, wb, wc)) = blend::barycentric(point, p[a], p[b], p[c]) {
+                    return Some(([cell[a], cell[b], cell[c]], [wa, wb, wc]));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn find_correspondences(image_points: &[&[glm::Vec2]], grids: &[Resolution]) -> Vec<Correspondence> {
+    let mut correspondences = vec![];
+    for (projector, points) in image_points.iter().enumerate() {
+        for (other, other_points) in image_points.iter().enumerate() {
+            if other == projector {
+                continue;
+            }
+            for (corner, &point) in points.iter().enumerate() {
+                if let Some((corners, weights)) = locate_in_grid(other_points, grids[other], point) {
+                    correspondences.push(Correspondence { projector: projector, corner: corner, other: other, corners: corners, weights: weights });
+                }
+            }
+        }
+    }
+    correspondences
+}
+
+/// Where the corner is, less where the other projector's warp puts the same camera point
+fn seam_offset(scene_coords: &[Vec<glm::Vec3>], c: &Correspondence) -> glm::Vec3 {
+    let mut interpolated = vec3(0., 0., 0.);
+    for m in 0..3 {
+        interpolated = interpolated + scene_coords[c.other][c.corners[m]] * c.weights[m];
+    }
+    scene_coords[c.projector][c.corner] - interpolated
+}
+
+fn seam_error(scene_coords: &[Vec<glm::Vec3>], correspondences: &[Correspondence]) -> SeamError {
+    let mut error = SeamError { samples: correspondences.len(), ..SeamError::default() };
+    let mut sum = 0_f32;
+    for c in correspondences.iter() {
+        let distance = length(seam_offset(scene_coords, c));
+        sum += distance * distance;
+        error.max = error.max.max(distance);
+    }
+    if !correspondences.is_empty() {
+        error.rms = (sum / correspondences.len() as f32).sqrt();
+    }
+    error
+}
+
+// The adjustment works in f64 throughout, the finite difference derivatives need the precision
+
+type V3 = [f64; 3];
+type M3 = [[f64; 3]; 3];
+
+fn add(a: V3, b: V3) -> V3 { [a[0] + b[0], a[1] + b[1], a[2] + b[2]] }
+fn sub(a: V3, b: V3) -> V3 { [a[0] - b[0], a[1] - b[1], a[2] - b[2]] }
+fn scale(a: V3, s: f64) -> V3 { [a[0] * s, a[1] * s, a[2] * s] }
+fn dot3(a: V3, b: V3) -> f64 { a[0] * b[0] + a[1] * b[1] + a[2] * b[2] }
+fn cross3(a: V3, b: V3) -> V3 { [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]] }
+fn norm(a: V3) -> f64 { dot3(a, a).sqrt() }
+fn unit(a: V3) -> V3 { scale(a, 1. / norm(a).max(1e-300)) }
+fn mul(m: &M3, v: V3) -> V3 { [dot3(m[0], v), dot3(m[1], v), dot3(m[2], v)] }
+fn mul_transposed(m: &M3, v: V3) -> V3 {
+    [m[0][0] * v[0] + m[1][0] * v[1] + m[2][0] * v[2], m[0][1] * v[0] + m[1][1] * v[1] + m[2][1] * v[2], m[0][2] * v[0] + m[1][2] * v[1] + m[2][2] * v[2]]
+}
+fn from_vec3(v: glm::Vec3) -> V3 { [v.x as f64, v.y as f64, v.z as f64] }
+fn to_vec3(v: V3) -> glm::Vec3 { vec3(v[0] as f32, v[1] as f32, v[2] as f32) }
+
+/// Rotation by a rotation vector, its length the angle in radians (Rodrigues' formula)
+fn rotation(w: V3) -> M3 {
+    let angle = norm(w);
+    if angle < 1e-12 {
+        // first order, so the derivatives at zero come out right
+        return [[1., -w[2], w[1]], [w[2], 1., -w[0]], [-w[1], w[0], 1.]];
+    }
+    let k = scale(w, 1. / angle);
+    let (s, c) = angle.sin_cos();
+    let t = 1. - c;
+    [
+        [c + k[0] * k[0] * t, k[0] * k[1] * t - k[2] * s, k[0] * k[2] * t + k[1] * s],
+        [k[1] * k[0] * t + k[2] * s, c + k[1] * k[1] * t, k[1] * k[2] * t - k[0] * s],
+        [k[2] * k[0] * t - k[1] * s, k[2] * k[1] * t + k[0] * s, c + k[2] * k[2] * t],
+    ]
+}
+
+/// Solve a square system by Gaussian elimination with partial pivoting, None if it's singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+        if !(a[pivot][col].abs() > 1e-300) {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let factor = a[row][col] / a[col][col];
+            if factor == 0. {
+                continue;
+            }
+            for k in col..n {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// How the camera saw the corners, the inverse of `surfaces::camera_to_scene`
+enum Lens {
+    /// equidistant fisheye filling the photo, looking up at the zenith
+    Fisheye,
+    /// perspective with a vertical field of view in radians
+    Perspective { fov: f64, forward: V3, up: V3 },
+}
+
+struct CameraModel {
+    lens: Lens,
+    position: V3,
+    width: f64,
+    height: f64,
+}
+
+impl CameraModel {
+    fn new(surface: &SurfaceType, physical_camera: &PhysicalCamera) -> CameraModel {
+        let calibration = &physical_camera.calibration;
+        let (width, height) = (calibration.image_width as f64, calibration.image_height as f64);
+        match surface {
+            // the dome model puts the camera at the centre, whatever its location says
+            SurfaceType::HemisphericalDome{..} => CameraModel { lens: Lens::Fisheye, position: [0.; 3], width: width, height: height },
+            SurfaceType::Wall => CameraModel {
+                lens: Lens::Perspective {
+                    fov: (calibration.fov as f64).to_radians(),
+                    forward: unit(from_vec3(physical_camera.look_at)),
+                    up: from_vec3(physical_camera.up_dir),
+                },
+                position: from_vec3(physical_camera.position),
+                width: width,
+                height: height,
+            }
+        }
+    }
+
+    /// Photo position of a scene point, with the camera moved by `offset` and turned by
+    /// `turn`
+    fn project(&self, offset: V3, turn: &M3, point: V3) -> Option<[f64; 2]> {
+        let d = sub(point, add(self.position, offset));
+        let (w, h) = (self.width, self.height);
+        match &self.lens {
+            Lens::Fisheye => {
+                let d = mul_transposed(turn, d);
+                let polar = (d[1] / norm(d).max(1e-300)).max(-1.).min(1.).acos();
+                let horizontal = (d[0] * d[0] + d[2] * d[2]).sqrt();
+                if horizontal < 1e-12 {
+                    return Some([w * 0.5, h * 0.5]);
+                }
+                // image x runs along scene z and image y along scene x
+                let r = polar / consts::FRAC_PI_2;
+                Some([w * 0.5 * (1. + d[2] / horizontal * r), h * 0.5 * (1. + d[0] / horizontal * r)])
+            }
+            Lens::Perspective { fov, forward, up } => {
+                let f = mul(turn, *forward);
+                let s = unit(cross3(f, mul(turn, *up)));
+                let u = cross3(s, f);
+                let depth = dot3(d, f);
+                if depth <= 0. {
+                    return None;
+                }
+                let tan = (fov * 0.5).tan();
+                let x = dot3(d, s) / (depth * tan * (w / h));
+                let y = dot3(d, u) / (depth * tan);
+                Some([(x + 1.) * 0.5 * w, h - (y + 1.) * 0.5 * h])
+            }
+        }
+    }
+}
+
+/// Parameters of the surface: radius of a dome, nothing for the wall at z = 0. The dome stays
+/// centred on the origin, moving it would be the same as moving the camera and projectors.
+fn surface_params(surface: &SurfaceType) -> Vec<f64> {
+    match surface {
+        SurfaceType::HemisphericalDome{radius} => vec![*radius as f64],
+        SurfaceType::Wall => vec![]
+    }
+}
+
+/// Where a ray from `origin` along `dir` meets the surface
+fn intersect(surface: &SurfaceType, params: &[f64], origin: V3, dir: V3) -> Option<V3> {
+    let dir = unit(dir);
+    let t = match surface {
+        SurfaceType::HemisphericalDome{..} => {
+            // projectors are inside the dome, so take the far root
+            let b = dot3(origin, dir);
+            let disc = b * b - (dot3(origin, origin) - params[0] * params[0]);
+            if disc < 0. { return None; }
+            -b + disc.sqrt()
+        }
+        SurfaceType::Wall => {
+            if dir[2].abs() < 1e-12 { return None; }
+            -origin[2] / dir[2]
+        }
+    };
+    if t > 0. { Some(add(origin, scale(dir, t))) } else { None }
+}
+
+/// Normalized projector position of a chessboard corner, origin top-left
+fn corner_uv(grid: Resolution, k: usize) -> (f64, f64) {
+    let (cols, rows) = (grid.width as usize, grid.height as usize);
+    ((k % cols + 1) as f64 / (cols + 1) as f64, (k / cols + 1) as f64 / (rows + 1) as f64)
+}
+
+/// Parameters per projector: its position, then the 3x3 matrix (row-major) taking a
+/// normalized projector position `(u, v, 1)` to the direction of its ray
+const PROJECTOR_PARAMS: usize = 12;
+
+/// Residual per unit the ray through the middle of a projector's frame is longer or shorter
+/// than 1. Scaling the matrix doesn't move any ray, this pins it.
+const RAY_SCALE_WEIGHT: f64 = 1000.;
+
+fn ray(params: &[f64], (u, v): (f64, f64)) -> V3 {
+    let h = &params[3..12];
+    [h[0] * u + h[1] * v + h[2], h[3] * u + h[4] * v + h[5], h[6] * u + h[7] * v + h[8]]
+}
+
+/// Projector parameters reproducing measured corners as closely as rays from `position` can:
+/// a homography from the projector to the plane facing the corners' mean direction
+fn fit_projector(position: V3, corners: &[glm::Vec3], grid: Resolution) -> Option<Vec<f64>> {
+    let dirs: Vec<V3> = corners.iter().map(|&c| unit(sub(from_vec3(c), position))).collect();
+    let m = unit(dirs.iter().fold([0.; 3], |sum, &d| add(sum, d)));
+    let helper = if m[0].abs() < 0.9 { [1., 0., 0.] } else { [0., 1., 0.] };
+    let e1 = unit(cross3(helper, m));
+    let e2 = cross3(m, e1);
+
+    // least squares for a homography with its last element 1
+    let mut ata = vec![vec![0.; 8]; 8];
+    let mut atb = vec![0.; 8];
+    for (k, &d) in dirs.iter().enumerate() {
+        let depth = dot3(d, m);
+        if depth <= 0. {
+            return None;
+        }
+        let (a, b) = (dot3(d, e1) / depth, dot3(d, e2) / depth);
+        let (u, v) = corner_uv(grid, k);
+        let rows = [([u, v, 1., 0., 0., 0., -a * u, -a * v], a), ([0., 0., 0., u, v, 1., -b * u, -b * v], b)];
+        for (row, target) in rows.iter() {
+            for i in 0..8 {
+                for j in 0..8 {
+                    ata[i][j] += row[i] * row[j];
+                }
+                atb[i] += row[i] * target;
+            }
+        }
+    }
+    let g = solve(ata, atb)?;
+    // scaled so the ray through the middle of the frame has unit length
+    let length = norm([0.5 * (g[0] + g[1]) + g[2], 0.5 * (g[3] + g[4]) + g[5], 0.5 * (g[6] + g[7]) + 1.]);
+    let g = [[g[0] / length, g[1] / length, g[2] / length], [g[3] / length, g[4] / length, g[5] / length], [g[6] / length, g[7] / length, 1. / length]];
+
+    // ray = [e1 e2 m] * g * (u, v, 1)
+    let mut params = position.to_vec();
+    for row in 0..3 {
+        for col in 0..3 {
+            params.push(e1[row] * g[0][col] + e2[row] * g[1][col] + m[row] * g[2][col]);
+        }
+    }
+    Some(params)
+}
+
+/// The bundle adjustment: parameters are the surface's, the camera's offset and turn, then
+/// each projector's. Residuals are the photo offsets of every corner, then the priors, then
+/// each projector's ray scale.
+struct Problem<'a> {
+    surface: SurfaceType,
+    camera: CameraModel,
+    image_points: &'a [&'a [glm::Vec2]],
+    grids: &'a [Resolution],
+    /// surface and camera parameters
+    globals: usize,
+    initial: Vec<f64>,
+    /// residual per unit change of each surface and camera parameter
+    prior_weights: Vec<f64>,
+}
+
+impl<'a> Problem<'a> {
+    fn projector<'p>(&self, params: &'p [f64], p: usize) -> &'p [f64] {
+        let start = self.globals + p * PROJECTOR_PARAMS;
+        &params[start..start + PROJECTOR_PARAMS]
+    }
+
+    fn scene_point(&self, params: &[f64], p: usize, k: usize) -> Option<V3> {
+        let projector = self.projector(params, p);
+        let origin = [projector[0], projector[1], projector[2]];
+        intersect(&self.surface, params, origin, ray(projector, corner_uv(self.grids[p], k)))
+    }
+
+    /// Photo offsets of projector `p`'s corners, None if any misses the surface or camera
+    fn projector_residuals(&self, params: &[f64], p: usize, out: &mut Vec<f64>) -> Option<()> {
+        let s = self.globals - 6;
+        let offset = [params[s], params[s + 1], params[s + 2]];
+        let turn = rotation([params[s + 3], params[s + 4], params[s + 5]]);
+        for (k, measured) in self.image_points[p].iter().enumerate() {
+            let predicted = self.camera.project(offset, &turn, self.scene_point(params, p, k)?)?;
+            out.push(predicted[0] - measured.x as f64);
+            out.push(predicted[1] - measured.y as f64);
+        }
+        Some(())
+    }
+
+    fn residuals(&self, params: &[f64]) -> Option<Vec<f64>> {
+        let mut out = vec![];
+        for p in 0..self.grids.len() {
+            self.projector_residuals(params, p, &mut out)?;
+        }
+        for i in 0..self.globals {
+            out.push((params[i] - self.initial[i]) * self.prior_weights[i]);
+        }
+        for p in 0..self.grids.len() {
+            out.push(self.ray_scale(params, p).0);
+        }
+        Some(out)
+    }
+
+    /// Residual holding projector `p`'s ray through the middle of its frame at unit length,
+    /// and its derivatives by the ray matrix
+    fn ray_scale(&self, params: &[f64], p: usize) -> (f64, [f64; 9]) {
+        let projector = self.projector(params, p);
+        let middle = ray(projector, (0.5, 0.5));
+        let length = norm(middle).max(1e-300);
+        let mut derivatives = [0.; 9];
+        for row in 0..3 {
+            for (col, &w) in [0.5, 0.5, 1.].iter().enumerate() {
+                derivatives[row * 3 + col] = RAY_SCALE_WEIGHT * middle[row] / length * w;
+            }
+        }
+        ((length - 1.) * RAY_SCALE_WEIGHT, derivatives)
+    }
+
+    fn cost(&self, params: &[f64]) -> Option<f64> {
+        self.residuals(params).map(|r| r.iter().map(|v| v * v).sum())
+    }
+
+    /// Reprojection error in pixels, RMS over the corners
+    fn reprojection_rms(&self, params: &[f64]) -> f64 {
+        let mut out = vec![];
+        for p in 0..self.grids.len() {
+            if self.projector_residuals(params, p, &mut out).is_none() {
+                return std::f64::INFINITY;
+            }
+        }
+        (out.iter().map(|v| v * v).sum::<f64>() / (out.len() / 2).max(1) as f64).sqrt()
+    }
+
+    /// Gauss-Newton normal equations from forward difference derivatives. Corners only depend
+    /// on the surface, the camera and their own projector, so each projector's parameters are
+    /// only varied against its own corners.
+    fn normal_equations(&self, params: &[f64], residuals: &[f64]) -> (Vec<Vec<f64>>, Vec<f64>) {
+        let n = params.len();
+        let mut jtj = vec![vec![0.; n]; n];
+        let mut jtr = vec![0.; n];
+        let step = |x: f64| 1e-6 * x.abs().max(1.);
+
+        let mut start = 0;
+        for p in 0..self.grids.len() {
+            let count = self.image_points[p].len() * 2;
+            let base = &residuals[start..start + count];
+            let own = self.globals + p * PROJECTOR_PARAMS;
+            let columns: Vec<usize> = (0..self.globals).chain(own..own + PROJECTOR_PARAMS).collect();
+            let mut derivatives = vec![];
+            for &j in columns.iter() {
+                let mut varied = params.to_vec();
+                let h = step(params[j]);
+                varied[j] += h;
+                let mut out = vec![];
+                let column: Vec<f64> = match self.projector_residuals(&varied, p, &mut out) {
+                    Some(()) => out.iter().zip(base.iter()).map(|(a, b)| (a - b) / h).collect(),
+                    None => vec![0.; count]
+                };
+                derivatives.push(column);
+            }
+            for (a, &i) in columns.iter().enumerate() {
+                jtr[i] += derivatives[a].iter().zip(base.iter()).map(|(d, r)| d * r).sum::<f64>();
+                for (b, &j) in columns.iter().enumerate() {
+                    jtj[i][j] += derivatives[a].iter().zip(derivatives[b].iter()).map(|(x, y)| x * y).sum::<f64>();
+                }
+            }
+            start += count;
+        }
+        for i in 0..self.globals {
+            let w = self.prior_weights[i];
+            jtj[i][i] += w * w;
+            jtr[i] += w * residuals[start + i];
+        }
+        for p in 0..self.grids.len() {
+            let (residual, derivatives) = self.ray_scale(params, p);
+            let first = self.globals + p * PROJECTOR_PARAMS + 3;
+            for a in 0..9 {
+                jtr[first + a] += derivatives[a] * residual;
+                for b in 0..9 {
+                    jtj[first + a][first + b] += derivatives[a] * derivatives[b];
+                }
+            }
+        }
+        (jtj, jtr)
+    }
+
+    /// Levenberg-Marquardt from `params`, returning the adjusted parameters
+    fn solve(&self, mut params: Vec<f64>, iterations: u32) -> Vec<f64> {
+        let mut cost = match self.cost(&params) {
+            Some(cost) => cost,
+            None => return params
+        };
+        let mut damping = 1e-3;
+        for iteration in 0..iterations {
+            let residuals = self.residuals(&params).unwrap();
+            let (jtj, jtr) = self.normal_equations(&params, &residuals);
+            let mut improved = false;
+            while damping < 1e12 {
+                let mut a = jtj.clone();
+                for i in 0..params.len() {
+                    a[i][i] += damping * (jtj[i][i] + 1e-9);
+                }
+                let step = match solve(a, jtr.iter().map(|g| -g).collect()) {
+                    Some(step) => step,
+                    None => { damping *= 10.; continue; }
+                };
+                let trial: Vec<f64> = params.iter().zip(step.iter()).map(|(p, d)| p + d).collect();
+                match self.cost(&trial) {
+                    Some(trial_cost) if trial_cost < cost => {
+                        let converged = cost - trial_cost < 1e-12 * cost.max(1e-12);
+                        params = trial;
+                        cost = trial_cost;
+                        damping = (damping * 0.1).max(1e-12);
+                        improved = !converged;
+                        break;
+                    }
+                    _ => damping *= 10.
+                }
+            }
+            if !improved {
+                debug!("bundle adjustment converged after {} iterations", iteration + 1);
+                break;
+            }
+        }
+        params
+    }
+}
+
+/// Bundle adjust the surface, the camera pose and every projector together, then move the
+/// corners to where the adjusted model puts them. Each projector is modelled as rays from a
+/// point, through its chessboard corners' positions in its frame, meeting the surface; the
+/// camera photographs those points with the model `surfaces::camera_to_scene` inverts.
+///
+/// What's minimised is the camera reprojection error: the distance in the photo between where
+/// each corner was detected and where the model puts it, with the surface and camera held
+/// near where they were measured by `options.stiffness`. The seam error is only reported, not
+/// minimised: it falls when the corners given disagree between projectors, and can rise a
+/// little as detection noise is taken out, because seams are matched at the detected points.
+/// The camera priors also fix what the photos can't, turning or moving the camera and every
+/// projector together about the dome's centre or along a wall.
+///
+/// `image_points` and `scene_coords` are each projector's corners in the undistorted photo
+/// and on the surface, row-major over its `grids` chessboard. A dome's radius is updated.
+/// Returns the seam error, how far apart overlapping warps put the same camera point, before
+/// and after.
+pub fn refine(surface: &mut SurfaceType, physical_camera: &PhysicalCamera, image_points: &[&[glm::Vec2]], scene_coords: &mut [Vec<glm::Vec3>], grids: &[Resolution], options: RefineOptions) -> (SeamError, SeamError) {
+    let correspondences = find_correspondences(image_points, grids);
+    let before = seam_error(scene_coords, &correspondences);
+    info!("seam error before refinement is {:.4} RMS, {:.4} max over {} overlapping corners", before.rms, before.max, before.samples);
+    if correspondences.is_empty() {
+        warn!("no projectors overlap, the seam error can't be measured");
+    }
+
+    let camera = CameraModel::new(surface, physical_camera);
+    let mut initial = surface_params(surface);
+    initial.extend_from_slice(&[0.; 6]); // camera offset and turn
+    let globals = initial.len();
+
+    // typical distance from the camera to the corners, what "1% of the surface" is relative to
+    let distances: Vec<f64> = scene_coords.iter().flat_map(|corners| corners.iter())
+        .map(|&c| norm(sub(from_vec3(c), camera.position)))
+        .collect();
+    let size = (distances.iter().sum::<f64>() / distances.len().max(1) as f64).max(1e-6);
+    let stiffness = options.stiffness as f64;
+    let mut prior_weights: Vec<f64> = vec![stiffness * 100. / size; globals - 3];
+    prior_weights.extend_from_slice(&[stiffness / 1_f64.to_radians(); 3]);
+
+    let mut params = initial.clone();
+    for (p, corners) in scene_coords.iter().enumerate() {
+        // start every projector at the camera, where the measured corners are fitted exactly
+        // by a homography
+        match fit_projector(camera.position, corners, grids[p]) {
+            Some(projector) => params.extend(projector),
+            None => {
+                warn!("projector {} can't be modelled from its corners, not refining", p);
+                return (before, before);
+            }
+        }
+    }
+    let problem = Problem {
+        surface: *surface,
+        camera: camera,
+        image_points: image_points,
+        grids: grids,
+        globals: globals,
+        initial: initial,
+        prior_weights: prior_weights,
+    };
+    let initial_rms = problem.reprojection_rms(&params);
+    let params = problem.solve(params, options.iterations);
+    info!("reprojection error {:.3} pixels RMS before bundle adjustment, {:.3} after", initial_rms, problem.reprojection_rms(&params));
+
+    let mut refined = vec![];
+    for (p, corners) in scene_coords.iter().enumerate() {
+        let points: Option<Vec<V3>> = (0..corners.len()).map(|k| problem.scene_point(&params, p, k)).collect();
+        match points {
+            Some(points) => refined.push(points),
+            None => {
+                warn!("adjusted projector {} misses the surface, not refining", p);
+                return (before, before);
+            }
+        }
+    }
+    if let SurfaceType::HemisphericalDome{radius} = surface {
+        info!("dome radius adjusted from {:.4} to {:.4}", radius, params[0]);
+        *surface = SurfaceType::HemisphericalDome{radius: params[0] as f32};
+    }
+    let s = globals - 6;
+    debug!("camera moved by {:?} and turned by {:.3} degrees", &params[s..s + 3], norm([params[s + 3], params[s + 4], params[s + 5]]).to_degrees());
+    for (corners, points) in scene_coords.iter_mut().zip(refined.into_iter()) {
+        for (corner, point) in corners.iter_mut().zip(points.into_iter()) {
+            *corner = to_vec3(point);
+        }
+    }
+
+    let after = seam_error(scene_coords, &correspondences);
+    info!("seam error after refinement is {:.4} RMS, {:.4} max", after.rms, after.max);
+    (before, after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_calibration::{Calibration, DistortionModel};
+    use crate::surfaces;
+    use opencv::core::Matx33d;
+
+    const RADIUS: f32 = 5.;
+
+    /// Fisheye camera at the centre of the dome, as the dome model expects
+    fn dome_camera() -> PhysicalCamera {
+        let matrix = Matx33d::from([360., 0., 360., 0., 360., 360., 0., 0., 1.]);
+        PhysicalCamera {
+            position: vec3(0., 0., 0.),
+            look_at: vec3(0., 1., 0.),
+            up_dir: vec3(0., 0., 1.),
+            calibration: Calibration::new(matrix, &[0.; 4], DistortionModel::RadialTangential, 720, 720),
+        }
+    }
+
+    /// Where the chessboard corners of a projector at `position` with a square `fov` degree
+    /// frame, aimed at `target`, land on the dome
+    fn projector_corners(position: V3, target: V3, fov: f64, grid: Resolution) -> Vec<V3> {
+        let f = unit(sub(target, position));
+        let s = unit(cross3(f, [0., 0., 1.]));
+        let u = cross3(s, f);
+        let tan = (fov.to_radians() * 0.5).tan();
+        let dome = [RADIUS as f64];
+        (0..(grid.width * grid.height) as usize).map(|k| {
+            let (x, y) = corner_uv(grid, k);
+            let dir = add(f, add(scale(s, (2. * x - 1.) * tan), scale(u, (1. - 2. * y) * tan)));
+            intersect(&SurfaceType::HemisphericalDome{radius: RADIUS}, &dome, position, dir).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn bundle_adjustment_removes_detection_noise() {
+        let camera = dome_camera();
+        let mut surface = SurfaceType::HemisphericalDome{radius: RADIUS};
+        let model = CameraModel::new(&surface, &camera);
+        let grid = Resolution { width: 9, height: 6 };
+        let truth = vec![
+            projector_corners([1., 0.5, 0.], [1.5, 4., 0.5], 40., grid),
+            projector_corners([-1., 0.5, 0.5], [2.5, 3.8, 1.5], 40., grid),
+        ];
+
+        // corners detected up to a pixel off, and put on the dome through the camera
+        let mut image_points = vec![];
+        let mut scene_coords = vec![];
+        for (p, corners) in truth.iter().enumerate() {
+            let points: Vec<glm::Vec2> = corners.iter().enumerate().map(|(k, &corner)| {
+                let pixel = model.project([0.; 3], &rotation([0.; 3]), corner).unwrap();
+                let noise = |seed: usize| ((seed * 7919 + p * 104_729) % 1000) as f64 / 1000. - 0.5;
+                vec2((pixel[0] + noise(2 * k)) as f32, (pixel[1] + noise(2 * k + 1)) as f32)
+            }).collect();
+            scene_coords.push(points.iter().map(|&point| surfaces::camera_to_scene(&surface, &camera, point, 720, 720).unwrap()).collect::<Vec<_>>());
+            image_points.push(points);
+        }
+        let mean_error = |scene_coords: &[Vec<glm::Vec3>]| {
+            let errors: Vec<f64> = scene_coords.iter().zip(truth.iter())
+                .flat_map(|(corners, truth)| corners.iter().zip(truth.iter()).map(|(&c, &t)| norm(sub(from_vec3(c), t))))
+                .collect();
+            errors.iter().sum::<f64>() / errors.len() as f64
+        };
+        let measured_error = mean_error(&scene_coords);
+
+        let image_points: Vec<&[glm::Vec2]> = image_points.iter().map(|p| p.as_slice()).collect();
+        let (before, after) = refine(&mut surface, &camera, &image_points, &mut scene_coords, &[grid, grid], RefineOptions::default());
+        let refined_error = mean_error(&scene_coords);
+
+        assert!(before.samples > 0, "the projectors should overlap");
+        assert!(refined_error < measured_error * 0.5, "corners are {} from the truth after refinement, {} before", refined_error, measured_error);
+        match surface {
+            SurfaceType::HemisphericalDome{radius} => assert!((radius - RADIUS).abs() < 0.05, "radius moved to {}", radius),
+            _ => unreachable!()
+        }
+        assert!(after.rms.is_finite());
+    }
+
+    #[test]
+    fn refinement_reconciles_a_projector_placed_from_the_wrong_pose() {
+        let camera = dome_camera();
+        let mut surface = SurfaceType::HemisphericalDome{radius: RADIUS};
+        let model = CameraModel::new(&surface, &camera);
+        let grid = Resolution { width: 9, height: 6 };
+        let truth = vec![
+            projector_corners([1., 0.5, 0.], [1.5, 4., 0.5], 40., grid),
+            projector_corners([-1., 0.5, 0.5], [2.5, 3.8, 1.5], 40., grid),
+        ];
+        let image_points: Vec<Vec<glm::Vec2>> = truth.iter().map(|corners| corners.iter().map(|&corner| {
+            let pixel = model.project([0.; 3], &rotation([0.; 3]), corner).unwrap();
+            vec2(pixel[0] as f32, pixel[1] as f32)
+        }).collect()).collect();
+        // the second projector's corners as if it were turned 2 degrees about the zenith, and
+        // on a dome 2% too big
+        let turn = rotation([0., 2_f64.to_radians(), 0.]);
+        let mut scene_coords: Vec<Vec<glm::Vec3>> = truth.iter().enumerate().map(|(p, corners)| {
+            corners.iter().map(|&corner| if p == 1 { to_vec3(scale(mul(&turn, corner), 1.02)) } else { to_vec3(corner) }).collect()
+        }).collect();
+
+        // projectors start with the ray through the middle of their frame at unit length, the
+        // scale the solver holds them to
+        let start = fit_projector([0.; 3], &scene_coords[1], grid).unwrap();
+        assert!((norm(ray(&start, (0.5, 0.5))) - 1.).abs() < 1e-9);
+
+        let image_points: Vec<&[glm::Vec2]> = image_points.iter().map(|p| p.as_slice()).collect();
+        let (before, after) = refine(&mut surface, &camera, &image_points, &mut scene_coords, &[grid, grid], RefineOptions::default());
+        assert!(before.samples > 0, "the projectors should overlap");
+        assert!(after.rms < before.rms * 0.1, "seam error {} after refinement, {} before", after.rms, before.rms);
+        let error = scene_coords.iter().zip(truth.iter())
+            .flat_map(|(corners, truth)| corners.iter().zip(truth.iter()).map(|(&c, &t)| norm(sub(from_vec3(c), t))))
+            .fold(0., f64::max);
+        assert!(error < 0.01, "corners are up to {} from the truth", error);
+        match surface {
+            SurfaceType::HemisphericalDome{radius} => assert!((radius - RADIUS).abs() < 0.01, "radius moved to {}", radius),
+            _ => unreachable!()
+        }
+    }
+}
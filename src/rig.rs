@@ -0,0 +1,68 @@
+
+use serde::Deserialize;
+use std::fs::File;
+use std::path::Path;
+
+/// One projector in a rig file
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProjectorEntry {
+    /// key for the projector's calibration in the combined output
+    pub id: String,
+    /// same forms as `--control-url`, without one the operator displays patterns by hand
+    #[serde(default)]
+    pub control_url: Option<String>,
+    /// projector output resolution, e.g. "1920x1080"
+    #[serde(default = "default_resolution")]
+    pub resolution: String,
+    /// chessboard pattern size, e.g. "25x16"
+    #[serde(default = "default_pattern_size")]
+    pub pattern_size: String,
+}
+
+fn default_resolution() -> String {
+    String::from("1024x768")
+}
+
+fn default_pattern_size() -> String {
+    String::from("25x16")
+}
+
+/// Projectors to calibrate in one run, e.g.
+///
+/// ```yaml
+/// projectors:
+///   - id: front
+///     control_url: http://10.0.0.11:8080
+///     resolution: 1920x1080
+///   - id: left
+///     control_url: http://10.0.0.12:8080
+///     resolution: 1920x1080
+///     pattern_size: 17x10
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct RigConfig {
+    pub projectors: Vec<ProjectorEntry>,
+}
+
+/// Load a rig file, YAML or JSON depending on the extension
+pub fn load_rig(fname: &str) -> Result<RigConfig, String> {
+    let file = File::open(fname).map_err(|e| format!("failed to open {}: {}", fname, e))?;
+    let is_yaml = Path::new(fname).extension()
+        .map(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"))
+        .unwrap_or(false);
+    let rig: RigConfig = if is_yaml {
+        serde_yaml::from_reader(file).map_err(|e| format!("failed to parse {}: {}", fname, e))?
+    } else {
+        serde_json::from_reader(file).map_err(|e| format!("failed to parse {}: {}", fname, e))?
+    };
+
+    if rig.projectors.is_empty() {
+        return Err(format!("{} doesn't list any projectors", fname));
+    }
+    for (i, entry) in rig.projectors.iter().enumerate() {
+        if rig.projectors[..i].iter().any(|other| other.id == entry.id) {
+            return Err(format!("projector ID {} is used more than once in {}", entry.id, fname));
+        }
+    }
+    Ok(rig)
+}
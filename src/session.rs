@@ -0,0 +1,138 @@
+
+use opencv::prelude::*;
+use opencv::types::*;
+use opencv::imgcodecs;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use super::photo::MANIFEST_FILE;
+
+/// Everything recorded during a session. The `photos` list is what `photo::DirectoryCamera`
+/// reads to replay the session.
+#[derive(Serialize, Debug, Default)]
+struct Manifest {
+    version: u32,
+    /// seconds since the unix epoch
+    created: u64,
+    /// command line the session was run with
+    args: Vec<String>,
+    /// copy of the camera calibration file
+    calibration: Option<String>,
+    /// patterns sent to the projector, in order
+    patterns: Vec<RecordedPattern>,
+    /// photos taken, in order
+    photos: Vec<RecordedPhoto>,
+    /// calibration JSON produced by the session
+    result: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct RecordedPattern {
+    pattern: String,
+    file: String,
+}
+
+#[derive(Serialize, Debug)]
+struct RecordedPhoto {
+    pattern: String,
+    /// photo as returned by the camera
+    file: String,
+    undistorted: Option<String>,
+}
+
+/// Archives every pattern, photo and parameter of a calibration run into a directory so that
+/// a bad calibration can be debugged, or rerun with `--camera dir:<dir>`, afterwards. The
+/// manifest is rewritten after every addition so an interrupted session is still usable.
+pub struct SessionRecorder {
+    dir: PathBuf,
+    manifest: RefCell<Manifest>,
+}
+
+impl SessionRecorder {
+    pub fn create(dir: &str, args: &[String]) -> io::Result<SessionRecorder> {
+        fs::create_dir_all(dir)?;
+        let manifest = Manifest {
+            version: 1,
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            args: args.to_vec(),
+            ..Manifest::default()
+        };
+        let recorder = SessionRecorder { dir: PathBuf::from(dir), manifest: RefCell::new(manifest) };
+        recorder.save()?;
+        info!("recording session to {}", dir);
+        Ok(recorder)
+    }
+
+    /// Keep a copy of the camera calibration file
+    pub fn record_calibration(&self, fname: &str) {
+        // drop any "#camera" selector, it's only meaningful on the command line
+        let path = Path::new(fname.split('#').next().unwrap());
+        let copy = format!("calibration-{}", path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default());
+        self.write(&copy, |target| fs::copy(path, target).map(|_| ()));
+        self.manifest.borrow_mut().calibration = Some(copy);
+        self.save_or_warn();
+    }
+
+    /// Keep an encoded pattern image that was sent to the projector
+    pub fn record_pattern(&self, pattern: &str, image_bytes: &[u8], format: &str) {
+        let index = self.manifest.borrow().patterns.len() + 1;
+        let file = format!("pattern-{:03}-{}.{}", index, pattern, format.trim_start_matches('.'));
+        self.write(&file, |target| fs::write(target, image_bytes));
+        self.manifest.borrow_mut().patterns.push(RecordedPattern { pattern: pattern.to_string(), file: file });
+        self.save_or_warn();
+    }
+
+    /// Keep a photo as it came from the camera along with its undistorted version. Saved as
+    /// PNG so no further compression artifacts are added.
+    pub fn record_photo(&self, pattern: &str, raw: &Mat, undistorted: Option<&Mat>) {
+        let index = self.manifest.borrow().photos.len() + 1;
+        let file = format!("photo-{:03}-{}.png", index, pattern);
+        self.write_image(&file, raw);
+        let undistorted_file = undistorted.map(|image| {
+            let file = format!("photo-{:03}-{}-undistorted.png", index, pattern);
+            self.write_image(&file, image);
+            file
+        });
+        self.manifest.borrow_mut().photos.push(RecordedPhoto { pattern: pattern.to_string(), file: file, undistorted: undistorted_file });
+        self.save_or_warn();
+    }
+
+    /// Keep the calibration JSON the session produced
+    pub fn record_result(&self, json: &str) {
+        let file = String::from("result.json");
+        self.write(&file, |target| fs::write(target, json));
+        self.manifest.borrow_mut().result = Some(file);
+        self.save_or_warn();
+    }
+
+    fn write_image(&self, file: &str, image: &Mat) {
+        self.write(file, |target| {
+            imgcodecs::imwrite(target.to_str().unwrap(), image, &VectorOfi32::new())
+                .map(|_| ())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        });
+    }
+
+    /// A failure to record shouldn't stop the calibration, so only warn about it
+    fn write<F: FnOnce(&Path) -> io::Result<()>>(&self, file: &str, writer: F) {
+        let target = self.dir.join(file);
+        if let Err(e) = writer(&target) {
+            warn!("failed to record {} in session: {}", target.display(), e);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&*self.manifest.borrow()).unwrap();
+        fs::write(self.dir.join(MANIFEST_FILE), json)
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            warn!("failed to update session manifest in {}: {}", self.dir.display(), e);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+
+use opencv::prelude::*;
+use opencv::types::*;
+use opencv::core::*;
+use opencv::imgproc::*;
+use opencv::calib3d::*;
+use opencv::imgcodecs;
+use glm::*;
+use glm::ext::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use log::{info, debug};
+use super::{PhysicalCamera, Resolution};
+use super::camera_calibration::DistortionModel;
+use super::photo::{Camera, CameraMetadata, PhotoError};
+use super::projector::{ProjectorControl, ProjectorError, ProjectorStatus};
+use super::surfaces::{self, SurfaceType};
+use super::math;
+
+/// Pose and intrinsics of a simulated projector
+#[derive(Clone, Copy, Debug)]
+pub struct ProjectorModel {
+    pub position: glm::Vec3,
+    pub look_at: glm::Vec3,
+    pub up: glm::Vec3,
+    /// vertical field of view in degrees
+    pub fov: f32,
+    pub resolution: Resolution,
+}
+
+impl ProjectorModel {
+    fn view_projection(&self) -> (glm::Mat4, glm::Mat4) {
+        let view = look_at(self.position, self.look_at, self.up);
+        let proj = perspective(radians(self.fov), self.resolution.aspect_ratio(), 0.1, 100.);
+        (view, proj)
+    }
+
+    /// Cast a ray from the projector through a pixel (origin top-left) onto the surface
+    pub fn surface_hit(&self, surface: &SurfaceType, x: f32, y: f32) -> Option<glm::Vec3> {
+        let (view, proj) = self.view_projection();
+        let (w, h) = (self.resolution.width as f32, self.resolution.height as f32);
+        let viewport = vec4(0., 0., w, h);
+        let near = math::un_project(vec3(x, h - y, 0.), &view, &proj, viewport).ok()?;
+        let far = math::un_project(vec3(x, h - y, 1.), &view, &proj, viewport).ok()?;
+        let dir = normalize(far - near);
+        let origin = self.position;
+
+        match surface {
+            SurfaceType::HemisphericalDome{radius} => {
+                // the projector is inside the sphere, so take the positive root
+                let b = dot(origin, dir);
+                let c = dot(origin, origin) - radius * radius;
+                let disc = b * b - c;
+                if disc < 0. { return None; }
+                let t = -b + disc.sqrt();
+                let hit = origin + dir * t;
+                if t > 0. && hit.y >= 0. { Some(hit) } else { None }
+            }
+            SurfaceType::Wall => {
+                // wall is at z = 0
+                if dir.z.abs() < 1e-6 { return None; }
+                let t = -origin.z / dir.z;
+                if t > 0. { Some(origin + dir * t) } else { None }
+            }
+        }
+    }
+
+    /// Where a scene point lands on the projector, normalized with the origin top-left
+    fn project(&self, scene_point: glm::Vec3) -> Option<glm::Vec2> {
+        let (view, proj) = self.view_projection();
+        let eye_space = view * scene_point.extend(1.);
+        if eye_space.z >= 0. {
+            return None; // behind the projector
+        }
+        let screen = math::project(scene_point, &view, &proj, vec4(0., 0., 1., 1.));
+        if screen.x < 0. || screen.y < 0. || screen.x > 1. || screen.y > 1. {
+            return None;
+        }
+        Some(vec2(screen.x, 1. - screen.y))
+    }
+}
+
+/// What the simulated projector is showing
+#[derive(Default)]
+struct Display {
+    current: Option<Mat>,
+}
+
+struct Scene {
+    camera_width: i32,
+    camera_height: i32,
+    /// for every raw camera pixel, the normalized projector position that lights it
+    pixel_map: Vec<Option<glm::Vec2>>,
+    /// brightness of unlit surface and of projector white
+    ambient: f64,
+    gain: f64,
+}
+
+/// Renders what the physical camera would photograph when a projector shines a pattern onto
+/// the surface, so the whole calibration can run without a dome. Pass `camera()` and
+/// `projector()` to `produce_calibration` in place of the real ones.
+///
+/// The camera to surface mapping is the same `surfaces::camera_to_scene` model the calibration
+/// uses, with the lens distortion from the camera's calibration applied on top, so the scene
+/// coordinates recovered should match `ground_truth_corners`.
+pub struct Simulation {
+    surface: SurfaceType,
+    projector: ProjectorModel,
+    scene: Rc<Scene>,
+    display: Rc<RefCell<Display>>,
+}
+
+impl Simulation {
+    pub fn new(surface: SurfaceType, projector: ProjectorModel, physical_camera: &PhysicalCamera) -> opencv::Result<Simulation> {
+        let calibration = &physical_camera.calibration;
+        let (w, h) = (calibration.image_width, calibration.image_height);
+
+        // find where every raw (distorted) pixel sits in the undistorted photo
+        let mut raw_points = VectorOfPoint2f::new();
+        for y in 0..h {
+            for x in 0..w {
+                raw_points.push(Point2f::new(x as f32, y as f32));
+            }
+        }
+        let mut undistorted = VectorOfPoint2f::new();
+        match calibration.distortion_model {
+            DistortionModel::RadialTangential => undistort_points(&raw_points, &mut undistorted, &calibration.camera_matrix, &calibration.distortion_coefficients, &Mat::default()?, &calibration.camera_matrix)?,
+            DistortionModel::Fisheye => fisheye_undistort_points(&raw_points, &mut undistorted, &calibration.camera_matrix, &calibration.distortion_coefficients, &Mat::default()?, &calibration.camera_matrix)?,
+        }
+
+        let pixel_map = undistorted.iter().map(|pt| {
+            surfaces::camera_to_scene(&surface, physical_camera, vec2(pt.x, pt.y), w, h).ok()
+                .and_then(|scene_point| projector.project(scene_point))
+        }).collect::<Vec<_>>();
+        info!("simulated camera sees the projector in {} of {} pixels", pixel_map.iter().filter(|p| p.is_some()).count(), pixel_map.len());
+
+        Ok(Simulation {
+            surface: surface,
+            projector: projector,
+            scene: Rc::new(Scene { camera_width: w, camera_height: h, pixel_map: pixel_map, ambient: 10., gain: 0.85 }),
+            display: Rc::new(RefCell::new(Display::default())),
+        })
+    }
+
+    pub fn projector(&self) -> SimulatedProjector {
+        SimulatedProjector { display: self.display.clone() }
+    }
+
+    pub fn camera(&self) -> SimulatedCamera {
+        SimulatedCamera { scene: self.scene.clone(), display: self.display.clone() }
+    }
+
+    /// Scene positions of the internal chessboard corners, in the row-major order the
+    /// calibration reports them, or None where a corner misses the surface
+    pub fn ground_truth_corners(&self, warp_res: Resolution) -> Vec<Option<glm::Vec3>> {
+        let (w, h) = (self.projector.resolution.width as f32, self.projector.resolution.height as f32);
+        let mut corners = vec![];
+        for j in 0..warp_res.height {
+            for i in 0..warp_res.width {
+                let x = (i + 1) as f32 / (warp_res.width + 1) as f32 * w;
+                let y = (j + 1) as f32 / (warp_res.height + 1) as f32 * h;
+                corners.push(self.projector.surface_hit(&self.surface, x, y));
+            }
+        }
+        corners
+    }
+}
+
+fn render(scene: &Scene, pattern: Option<&Mat>) -> opencv::Result<Mat> {
+    let mut photo = Mat::new_rows_cols_with_default(scene.camera_height, scene.camera_width, CV_8UC3, Scalar::all(scene.ambient))?;
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        None => return Ok(photo) // projector is dark
+    };
+    let mut bgr = Mat::default()?;
+    if pattern.channels()? == 1 {
+        cvt_color(pattern, &mut bgr, COLOR_GRAY2BGR, 0)?;
+    } else {
+        pattern.copy_to(&mut bgr)?;
+    }
+    let (cols, rows) = (bgr.cols(), bgr.rows());
+
+    for (i, lit_by) in scene.pixel_map.iter().enumerate() {
+        if let Some(p) = lit_by {
+            let px = ((p.x * cols as f32) as i32).min(cols - 1);
+            let py = ((p.y * rows as f32) as i32).min(rows - 1);
+            let source = *bgr.at_2d::<Vec3b>(py, px)?;
+            let target = photo.at_2d_mut::<Vec3b>(i as i32 / scene.camera_width, i as i32 % scene.camera_width)?;
+            for c in 0..3 {
+                target[c] = (scene.ambient + scene.gain * source[c] as f64).min(255.) as u8;
+            }
+        }
+    }
+    Ok(photo)
+}
+
+/// Stand-in for the projector in a `Simulation`
+#[derive(Clone)]
+pub struct SimulatedProjector {
+    display: Rc<RefCell<Display>>,
+}
+
+impl ProjectorControl for SimulatedProjector {
+    fn show_image(&mut self, image_bytes: &[u8], _format: &str) -> Result<(), ProjectorError> {
+        let decoded = Mat::from_slice(image_bytes)
+            .and_then(|buf| imgcodecs::imdecode(&buf, imgcodecs::IMREAD_COLOR))
+            .map_err(|e| ProjectorError::Failed(e.to_string()))?;
+        self.display.borrow_mut().current = Some(decoded);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), ProjectorError> {
+        self.display.borrow_mut().current = None;
+        Ok(())
+    }
+
+    fn push_calibration(&mut self, _json: &str) -> Result<(), ProjectorError> {
+        Err(ProjectorError::Unsupported("receiving a calibration"))
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        let showing = if self.display.borrow().current.is_some() { "showing a pattern" } else { "dark" };
+        Ok(ProjectorStatus { online: true, message: Some(format!("simulated projector is {}", showing)), ..ProjectorStatus::default() })
+    }
+
+    fn wait_until_displayed(&mut self, _timeout: Duration) -> Result<(), ProjectorError> {
+        Ok(()) // frames are on screen as soon as they're shown
+    }
+}
+
+/// Camera photographing a `Simulation`
+pub struct SimulatedCamera {
+    scene: Rc<Scene>,
+    display: Rc<RefCell<Display>>,
+}
+
+impl Camera for SimulatedCamera {
+    fn capture(&mut self) -> Result<Mat, PhotoError> {
+        let display = self.display.borrow();
+        render(&self.scene, display.current.as_ref()).map_err(|e| PhotoError::Camera(e.to_string()))
+    }
+
+    fn capture_pattern(&mut self, pattern: &str) -> Result<Mat, PhotoError> {
+        debug!("rendering simulated photo of {}", pattern);
+        self.capture()
+    }
+
+    fn metadata(&self) -> CameraMetadata {
+        CameraMetadata { backend: String::from("simulator"), source: String::from("simulation"), live: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera_calibration, produce_calibration, DisplaySync, WarpMode};
+    use std::fs;
+
+    #[test]
+    fn calibration_recovers_ground_truth_corners() {
+        // the dome model treats the photo as a square equidistant fisheye of the whole dome
+        let dir = tempfile::tempdir().unwrap();
+        let camera_cal = dir.path().join("cameras.txt").display().to_string();
+        fs::write(&camera_cal, "1 PINHOLE 720 720 360 360 360 360\n").unwrap();
+        let physical_camera = PhysicalCamera {
+            position: vec3(0., 0., 0.),
+            look_at: vec3(0., 1., 0.),
+            up_dir: vec3(0., 0., 1.),
+            calibration: camera_calibration::load_calibration(&camera_cal).unwrap(),
+        };
+
+        let radius = 5.;
+        let surface = SurfaceType::HemisphericalDome{radius: radius};
+        let projector_res = Resolution { width: 640, height: 400 };
+        let projector = ProjectorModel { position: vec3(0., 0.5, 0.), look_at: vec3(1., 4., 0.5), up: vec3(0., 0., 1.), fov: 50., resolution: projector_res };
+        let simulation = Simulation::new(surface, projector, &physical_camera).unwrap();
+
+        // width odd and height even so the detected corners can't come back rotated
+        let warp_res = Resolution { width: 9, height: 6 };
+        let json = produce_calibration(surface, &camera_cal, &mut simulation.projector(), DisplaySync::default(), &mut simulation.camera(),
+            None, vec3(0., 0., 0.), warp_res, projector_res, WarpMode::Perspective, None, None, None);
+        let calibration: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let scene = calibration["scene"].as_array().unwrap();
+        let truth = simulation.ground_truth_corners(warp_res);
+        assert_eq!(scene.len(), truth.len());
+
+        // a pixel of the fisheye is about a quarter of a degree, or 0.02 across the dome,
+        // allow for a few
+        for (k, (point, expected)) in scene.iter().zip(truth.iter()).enumerate() {
+            let p: Vec<f32> = point.as_array().unwrap().iter().map(|v| v.as_f64().unwrap() as f32).collect();
+            let expected = expected.expect("simulated projector should light the dome at every corner");
+            let error = distance(vec3(p[0], p[1], p[2]), expected);
+            assert!(error < 0.1, "corner {} calibrated at {:?}, expected {:?}", k, p, expected);
+        }
+    }
+}
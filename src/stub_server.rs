@@ -0,0 +1,184 @@
+
+use serde_json::json;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use log::{info, warn, debug};
+
+/// What the stub has been sent
+#[derive(Clone, Debug, Default)]
+pub struct StubState {
+    /// number of images shown so far, also the frame number of the last one
+    pub frame: u64,
+    /// last image posted to `/show_image` and its format ("png" etc.)
+    pub pattern: Option<Vec<u8>>,
+    pub pattern_format: Option<String>,
+    /// last JSON posted to `/set_calibration`
+    pub calibration: Option<String>,
+    /// every command other than `show_image`, with its body, in the order received
+    pub commands: Vec<(String, String)>,
+}
+
+/// Stand-in for the media server, implementing the endpoints `network::ControlClient` uses:
+/// `POST /show_image`, `POST /<command>`, `GET /displayed` and `GET /status`. The last
+/// pattern and calibration can also be fetched back with `GET /pattern` and
+/// `GET /calibration`, or read from `state()` when it's run in-process.
+pub struct StubServer {
+    server: Server,
+    state: Arc<Mutex<StubState>>,
+    /// also write patterns and calibrations here as they arrive
+    output_dir: Option<PathBuf>,
+}
+
+/// A stub server running on a background thread
+pub struct StubHandle {
+    url: String,
+    state: Arc<Mutex<StubState>>,
+}
+
+impl StubHandle {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn state(&self) -> StubState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+fn json_response(body: serde_json::Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body.to_string())
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(message).with_status_code(status)
+}
+
+impl StubServer {
+    /// Listen on `addr`, e.g. "127.0.0.1:8080". Port 0 picks a free port.
+    pub fn bind(addr: &str, output_dir: Option<&str>) -> Result<StubServer, String> {
+        let server = Server::http(addr).map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+        if let Some(dir) = output_dir {
+            fs::create_dir_all(dir).map_err(|e| format!("failed to create {}: {}", dir, e))?;
+        }
+        Ok(StubServer {
+            server: server,
+            state: Arc::new(Mutex::new(StubState::default())),
+            output_dir: output_dir.map(PathBuf::from),
+        })
+    }
+
+    /// URL to pass as `--control-url`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.server.server_addr())
+    }
+
+    pub fn state(&self) -> StubState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Serve requests until the process exits
+    pub fn run(self) {
+        info!("stub control server listening on {}", self.url());
+        for request in self.server.incoming_requests() {
+            self.handle(request);
+        }
+    }
+
+    /// Serve requests on a background thread
+    pub fn spawn(self) -> StubHandle {
+        let handle = StubHandle { url: self.url(), state: self.state.clone() };
+        thread::spawn(move || self.run());
+        handle
+    }
+
+    fn handle(&self, mut request: Request) {
+        let url = request.url().to_string();
+        let path = url.split('?').next().unwrap_or("").trim_start_matches('/').to_string();
+        let method = request.method().clone();
+        debug!("stub server: {} /{}", method, path);
+
+        let mut body = vec![];
+        if let Err(e) = request.as_reader().read_to_end(&mut body) {
+            warn!("stub server failed to read request body: {}", e);
+            request.respond(error_response(400, "failed to read body")).ok();
+            return;
+        }
+        let content_type = request.headers().iter()
+            .find(|h| h.field.equiv("Content-Type"))
+            .map(|h| h.value.as_str().to_string())
+            .unwrap_or_default();
+
+        let response = match (&method, path.as_str()) {
+            (Method::Post, "show_image") => {
+                let format = content_type.trim_start_matches("image/").to_string();
+                let frame = self.show_image(body, if format.is_empty() { "png" } else { &format });
+                json_response(json!({ "frame": frame }))
+            }
+            (Method::Post, command) => {
+                let body = String::from_utf8_lossy(&body).into_owned();
+                if command == "set_calibration" {
+                    if let Err(e) = serde_json::from_str::<serde_json::Value>(&body) {
+                        request.respond(error_response(400, &format!("calibration isn't valid JSON: {}", e))).ok();
+                        return;
+                    }
+                    self.set_calibration(&body);
+                }
+                self.state.lock().unwrap().commands.push((command.to_string(), body));
+                json_response(json!({ "ok": true }))
+            }
+            // frames are "displayed" as soon as they arrive
+            (Method::Get, "displayed") => json_response(json!({ "displayed": true })),
+            (Method::Get, "status") => {
+                let state = self.state.lock().unwrap();
+                json_response(json!({
+                    "online": true,
+                    "displayed_frame": if state.frame > 0 { Some(state.frame) } else { None },
+                    "message": "stub control server",
+                }))
+            }
+            (Method::Get, "pattern") => match self.state.lock().unwrap().pattern.clone() {
+                Some(pattern) => Response::from_data(pattern),
+                None => error_response(404, "no pattern has been shown")
+            },
+            (Method::Get, "calibration") => match self.state.lock().unwrap().calibration.clone() {
+                Some(calibration) => json_response(serde_json::from_str(&calibration).unwrap()),
+                None => error_response(404, "no calibration has been received")
+            },
+            _ => error_response(404, "unknown endpoint")
+        };
+        if let Err(e) = request.respond(response) {
+            warn!("stub server failed to reply: {}", e);
+        }
+    }
+
+    fn show_image(&self, image: Vec<u8>, format: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.frame += 1;
+        if let Some(dir) = &self.output_dir {
+            let target = dir.join(format!("pattern-{:03}.{}", state.frame, format));
+            if let Err(e) = fs::write(&target, &image) {
+                warn!("failed to write {}: {}", target.display(), e);
+            }
+        }
+        info!("showing frame {} ({} bytes of {})", state.frame, image.len(), format);
+        state.pattern = Some(image);
+        state.pattern_format = Some(format.to_string());
+        state.frame
+    }
+
+    fn set_calibration(&self, json: &str) {
+        if let Some(dir) = &self.output_dir {
+            let target = dir.join("calibration.json");
+            if let Err(e) = fs::write(&target, json) {
+                warn!("failed to write {}: {}", target.display(), e);
+            }
+        }
+        info!("received calibration");
+        self.state.lock().unwrap().calibration = Some(json.to_string());
+    }
+}
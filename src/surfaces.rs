@@ -7,6 +7,7 @@ use std::f32::consts;
 use log::{debug};
 
 
+#[derive(Clone, Copy, Debug)]
 pub enum SurfaceType {
     HemisphericalDome {radius: f32},
     Wall
@@ -88,3 +89,35 @@ fn camera_to_scene_wall(camera: &PhysicalCamera, pt: glm::Vec2, image_width: i32
 
     Ok(scene_pt2)
 }
+
+/// Flatten a point on the surface into 2D, continuously over the whole surface. The dome uses
+/// an azimuthal equidistant (domemaster style) chart, the zenith at the origin and the horizon
+/// on the unit circle. The wall uses its own x and y.
+pub fn surface_chart(surface_type: &SurfaceType, point: glm::Vec3) -> glm::Vec2 {
+    match surface_type {
+        SurfaceType::HemisphericalDome{..} => {
+            let dir = normalize(point);
+            let polar = dir.y.max(-1.).min(1.).acos(); // angle away from the zenith
+            let horizontal = (dir.x * dir.x + dir.z * dir.z).sqrt();
+            if horizontal < 1e-6 {
+                return vec2(0., 0.);
+            }
+            let r = polar / (consts::PI / 2.);
+            vec2(dir.x / horizontal * r, dir.z / horizontal * r)
+        }
+        SurfaceType::Wall => vec2(point.x, point.y)
+    }
+}
+
+/// Nearest point on the surface
+pub fn project_to_surface(surface_type: &SurfaceType, point: glm::Vec3) -> glm::Vec3 {
+    match surface_type {
+        SurfaceType::HemisphericalDome{radius} => {
+            if length(point) < 1e-6 {
+                return point;
+            }
+            normalize(point) * *radius
+        }
+        SurfaceType::Wall => vec3(point.x, point.y, 0.)
+    }
+}
@@ -0,0 +1,504 @@
+
+use super::photo::{CameraSettings, PhotoError};
+use log::{info, debug};
+use std::time::{Duration, Instant};
+#[cfg(not(feature = "native-gphoto2"))]
+use std::collections::HashMap;
+#[cfg(not(feature = "native-gphoto2"))]
+use std::io::ErrorKind;
+#[cfg(not(feature = "native-gphoto2"))]
+use std::process::{Child, Command, Stdio};
+
+/// Location of a file on the camera's storage
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraFilePath {
+    pub folder: String,
+    pub name: String,
+}
+
+/// Events reported by a tethered camera while waiting for a capture
+#[derive(Debug, PartialEq)]
+pub enum TetherEvent {
+    FileAdded(CameraFilePath),
+    CaptureComplete,
+    Timeout,
+    Other,
+}
+
+/// The operations needed to drive a tethered camera. Implemented natively on top of
+/// libgphoto2, and by stand-in cameras when testing the capture sequence.
+pub trait TetheredDevice {
+    /// Set a camera configuration value by its gphoto2 name (e.g. "iso", "shutterspeed")
+    fn set_config(&mut self, name: &str, value: &str) -> Result<(), PhotoError>;
+    /// Fire the shutter without waiting for the image
+    fn trigger_capture(&mut self) -> Result<(), PhotoError>;
+    /// Block until the camera reports an event or the timeout expires
+    fn wait_for_event(&mut self, timeout: Duration) -> Result<TetherEvent, PhotoError>;
+    /// Download a file from the camera
+    fn download(&mut self, path: &CameraFilePath) -> Result<Vec<u8>, PhotoError>;
+}
+
+fn is_jpeg(path: &CameraFilePath) -> bool {
+    let name = path.name.to_ascii_lowercase();
+    name.ends_with(".jpg") || name.ends_with(".jpeg")
+}
+
+/// Apply the camera settings, take a photo and download it. Rather than sleeping for a
+/// fixed time this waits for the camera to report the new file, which is what makes the
+/// capture reliable on the Sony a5100 and a6000. Cameras shooting RAW+JPEG add two files,
+/// the JPEG is downloaded whichever order they arrive in.
+pub fn capture(device: &mut dyn TetheredDevice, settings: &CameraSettings, timeout: Duration) -> Result<Vec<u8>, PhotoError> {
+    for (name, value) in settings.config_values() {
+        debug!("setting camera {} to {}", name, value);
+        device.set_config(name, value)?;
+    }
+
+    device.trigger_capture()?;
+
+    // once a JPEG has arrived only hang around briefly for the capture complete event, some
+    // cameras never send one
+    let deadline = Instant::now() + timeout;
+    let mut added: Vec<CameraFilePath> = vec![];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let remaining = deadline - now;
+        let has_jpeg = added.iter().any(is_jpeg);
+        let wait = if has_jpeg { remaining.min(Duration::from_millis(500)) } else { remaining };
+        match device.wait_for_event(wait)? {
+            TetherEvent::FileAdded(path) => {
+                debug!("camera added file {}/{}", path.folder, path.name);
+                added.push(path);
+            }
+            TetherEvent::CaptureComplete => {
+                if !added.is_empty() { break; }
+            }
+            TetherEvent::Timeout => {
+                if has_jpeg { break; }
+            }
+            TetherEvent::Other => {}
+        }
+    }
+
+    // the latest JPEG, otherwise whatever the camera added last
+    let path = added.iter().rev().find(|path| is_jpeg(path)).or_else(|| added.last()).ok_or(PhotoError::Timeout)?.clone();
+    let data = device.download(&path)?;
+    info!("downloaded {} bytes from tethered camera", data.len());
+    Ok(data)
+}
+
+/// Tethered camera driven through the gphoto2 command line app, for builds without native
+/// libgphoto2 support. Each capture is one gphoto2 run that fires the shutter and downloads
+/// every file the camera adds until it reports the capture complete. Each downloaded file is
+/// reported as it appears.
+///
+/// This used to capture twice and keep the second photo, because downloading the first one
+/// wasn't reliable on Sony cameras. Waiting for the camera's file added event fixes that, so
+/// one capture is enough.
+#[cfg(not(feature = "native-gphoto2"))]
+pub struct CommandLineDevice {
+    config: Vec<(String, String)>,
+    dir: tempfile::TempDir,
+    process: Option<Child>,
+    /// set once gphoto2 has exited
+    finished: bool,
+    /// downloaded files already reported
+    reported: Vec<String>,
+    /// size of each downloaded file when last looked at, they're reported once it stops changing
+    sizes: HashMap<String, u64>,
+}
+
+#[cfg(not(feature = "native-gphoto2"))]
+impl CommandLineDevice {
+    pub fn new() -> Result<CommandLineDevice, PhotoError> {
+        Ok(CommandLineDevice { config: vec![], dir: tempfile::tempdir()?, process: None, finished: false, reported: vec![], sizes: HashMap::new() })
+    }
+
+    /// The oldest downloaded file not reported yet, if gphoto2 has finished writing it
+    fn next_download(&mut self) -> Result<Option<CameraFilePath>, PhotoError> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(self.dir.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with("capture") && !self.reported.contains(&name) {
+                let metadata = entry.metadata()?;
+                files.push((metadata.modified()?, name, metadata.len()));
+            }
+        }
+        files.sort();
+
+        let (_, name, size) = match files.into_iter().next() {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let settled = self.finished || (size > 0 && self.sizes.get(&name) == Some(&size));
+        self.sizes.insert(name.clone(), size);
+        if !settled {
+            return Ok(None);
+        }
+        self.reported.push(name.clone());
+        Ok(Some(CameraFilePath { folder: self.dir.path().display().to_string(), name: name }))
+    }
+}
+
+#[cfg(not(feature = "native-gphoto2"))]
+impl TetheredDevice for CommandLineDevice {
+    fn set_config(&mut self, name: &str, value: &str) -> Result<(), PhotoError> {
+        // passed to gphoto2 along with the capture
+        self.config.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    fn trigger_capture(&mut self) -> Result<(), PhotoError> {
+        let mut args = vec![];
+        for (name, value) in self.config.drain(..) {
+            args.push(String::from("--set-config"));
+            args.push(format!("{}={}", name, value));
+        }
+        // the camera's file name keeps the RAW and JPEG of one shot apart
+        let fname = self.dir.path().join("capture-%f.%C");
+        args.extend(["--trigger-capture", "--wait-event-and-download=CAPTURECOMPLETE", "--force-overwrite", "--filename"].iter().map(|a| a.to_string()));
+        args.push(fname.display().to_string());
+
+        let child = Command::new("gphoto2")
+            .args(&args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => PhotoError::NotFound(String::from("gphoto2 executable not found. Is the gphoto2 package not installed?")),
+                _ => PhotoError::from(err)
+            })?;
+        self.process = Some(child);
+        self.finished = false;
+        Ok(())
+    }
+
+    fn wait_for_event(&mut self, timeout: Duration) -> Result<TetherEvent, PhotoError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.finished {
+                let exited = match self.process.as_mut() {
+                    Some(child) => child.try_wait()?.is_some(),
+                    None => return Err(PhotoError::Camera(String::from("no capture in progress"))),
+                };
+                if exited {
+                    let output = self.process.take().unwrap().wait_with_output()?;
+                    debug!("gphoto2 stdout: {}", String::from_utf8_lossy(&output.stdout));
+                    debug!("gphoto2 stderr: {}", String::from_utf8_lossy(&output.stderr));
+                    if !output.status.success() {
+                        return Err(PhotoError::Camera(format!("gphoto2 failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+                    }
+                    self.finished = true;
+                }
+            }
+            if let Some(path) = self.next_download()? {
+                return Ok(TetherEvent::FileAdded(path));
+            }
+            if self.finished {
+                return Ok(TetherEvent::CaptureComplete);
+            }
+            if Instant::now() >= deadline {
+                return Ok(TetherEvent::Timeout);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn download(&mut self, path: &CameraFilePath) -> Result<Vec<u8>, PhotoError> {
+        Ok(std::fs::read(std::path::Path::new(&path.folder).join(&path.name))?)
+    }
+}
+
+#[cfg(not(feature = "native-gphoto2"))]
+impl Drop for CommandLineDevice {
+    fn drop(&mut self) {
+        // a capture that timed out
+        if let Some(mut child) = self.process.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(feature = "native-gphoto2")]
+pub use native::Gphoto2Device;
+
+#[cfg(feature = "native-gphoto2")]
+mod native {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_float, c_int, c_ulong, c_void};
+    use std::ptr;
+
+    #[repr(C)] struct GPContext { _private: [u8; 0] }
+    #[repr(C)] struct Camera { _private: [u8; 0] }
+    #[repr(C)] struct CameraFile { _private: [u8; 0] }
+    #[repr(C)] struct CameraWidget { _private: [u8; 0] }
+
+    #[repr(C)]
+    struct GPCameraFilePath {
+        name: [c_char; 128],
+        folder: [c_char; 1024],
+    }
+
+    const GP_OK: c_int = 0;
+    const GP_EVENT_FILE_ADDED: c_int = 2;
+    const GP_EVENT_CAPTURE_COMPLETE: c_int = 4;
+    const GP_EVENT_TIMEOUT: c_int = 1;
+    const GP_FILE_TYPE_NORMAL: c_int = 1;
+    const GP_WIDGET_RANGE: c_int = 3;
+    const GP_WIDGET_TOGGLE: c_int = 4;
+
+    #[link(name = "gphoto2")]
+    extern "C" {
+        fn gp_context_new() -> *mut GPContext;
+        fn gp_context_unref(context: *mut GPContext);
+        fn gp_camera_new(camera: *mut *mut Camera) -> c_int;
+        fn gp_camera_init(camera: *mut Camera, context: *mut GPContext) -> c_int;
+        fn gp_camera_exit(camera: *mut Camera, context: *mut GPContext) -> c_int;
+        fn gp_camera_unref(camera: *mut Camera) -> c_int;
+        fn gp_camera_trigger_capture(camera: *mut Camera, context: *mut GPContext) -> c_int;
+        fn gp_camera_wait_for_event(camera: *mut Camera, timeout: c_int, eventtype: *mut c_int, eventdata: *mut *mut c_void, context: *mut GPContext) -> c_int;
+        fn gp_camera_file_get(camera: *mut Camera, folder: *const c_char, file: *const c_char, type_: c_int, camera_file: *mut CameraFile, context: *mut GPContext) -> c_int;
+        fn gp_camera_get_single_config(camera: *mut Camera, name: *const c_char, widget: *mut *mut CameraWidget, context: *mut GPContext) -> c_int;
+        fn gp_camera_set_single_config(camera: *mut Camera, name: *const c_char, widget: *mut CameraWidget, context: *mut GPContext) -> c_int;
+        fn gp_file_new(file: *mut *mut CameraFile) -> c_int;
+        fn gp_file_unref(file: *mut CameraFile) -> c_int;
+        fn gp_file_get_data_and_size(file: *mut CameraFile, data: *mut *const c_char, size: *mut c_ulong) -> c_int;
+        fn gp_widget_get_type(widget: *mut CameraWidget, type_: *mut c_int) -> c_int;
+        fn gp_widget_set_value(widget: *mut CameraWidget, value: *const c_void) -> c_int;
+        fn gp_widget_free(widget: *mut CameraWidget) -> c_int;
+        fn gp_result_as_string(result: c_int) -> *const c_char;
+    }
+
+    fn check(result: c_int, action: &str) -> Result<(), PhotoError> {
+        if result >= GP_OK {
+            Ok(())
+        } else {
+            let reason = unsafe { CStr::from_ptr(gp_result_as_string(result)) }.to_string_lossy();
+            Err(PhotoError::Camera(format!("{} failed: {}", action, reason)))
+        }
+    }
+
+    /// First camera found on USB, driven through libgphoto2
+    pub struct Gphoto2Device {
+        context: *mut GPContext,
+        camera: *mut Camera,
+    }
+
+    impl Gphoto2Device {
+        pub fn autodetect() -> Result<Gphoto2Device, PhotoError> {
+            unsafe {
+                let context = gp_context_new();
+                let mut camera = ptr::null_mut();
+                check(gp_camera_new(&mut camera), "gp_camera_new")?;
+                let device = Gphoto2Device { context: context, camera: camera };
+                check(gp_camera_init(camera, context), "camera init (is a camera connected and not mounted?)")?;
+                Ok(device)
+            }
+        }
+    }
+
+    impl Drop for Gphoto2Device {
+        fn drop(&mut self) {
+            unsafe {
+                gp_camera_exit(self.camera, self.context);
+                gp_camera_unref(self.camera);
+                gp_context_unref(self.context);
+            }
+        }
+    }
+
+    impl TetheredDevice for Gphoto2Device {
+        fn set_config(&mut self, name: &str, value: &str) -> Result<(), PhotoError> {
+            let c_name = CString::new(name).unwrap();
+            let c_value = CString::new(value).unwrap();
+            unsafe {
+                let mut widget = ptr::null_mut();
+                check(gp_camera_get_single_config(self.camera, c_name.as_ptr(), &mut widget, self.context), &format!("reading camera config {}", name))?;
+                let mut widget_type = 0;
+                gp_widget_get_type(widget, &mut widget_type);
+                let result = match widget_type {
+                    GP_WIDGET_RANGE => {
+                        let v: c_float = value.parse().map_err(|_| PhotoError::Camera(format!("{} needs a number", name)))?;
+                        gp_widget_set_value(widget, &v as *const c_float as *const c_void)
+                    }
+                    GP_WIDGET_TOGGLE => {
+                        let v: c_int = value.parse().map_err(|_| PhotoError::Camera(format!("{} needs 0 or 1", name)))?;
+                        gp_widget_set_value(widget, &v as *const c_int as *const c_void)
+                    }
+                    // text, radio and menu widgets take a string
+                    _ => gp_widget_set_value(widget, c_value.as_ptr() as *const c_void)
+                };
+                let result = check(result, &format!("setting {} to {}", name, value))
+                    .and_then(|_| check(gp_camera_set_single_config(self.camera, c_name.as_ptr(), widget, self.context), &format!("writing camera config {}", name)));
+                gp_widget_free(widget);
+                result
+            }
+        }
+
+        fn trigger_capture(&mut self) -> Result<(), PhotoError> {
+            unsafe { check(gp_camera_trigger_capture(self.camera, self.context), "trigger capture") }
+        }
+
+        fn wait_for_event(&mut self, timeout: Duration) -> Result<TetherEvent, PhotoError> {
+            unsafe {
+                let mut event_type = 0;
+                let mut event_data = ptr::null_mut();
+                check(gp_camera_wait_for_event(self.camera, timeout.as_millis() as c_int, &mut event_type, &mut event_data, self.context), "waiting for camera event")?;
+                let event = match event_type {
+                    GP_EVENT_FILE_ADDED => {
+                        let path = &*(event_data as *const GPCameraFilePath);
+                        TetherEvent::FileAdded(CameraFilePath {
+                            folder: CStr::from_ptr(path.folder.as_ptr()).to_string_lossy().into_owned(),
+                            name: CStr::from_ptr(path.name.as_ptr()).to_string_lossy().into_owned(),
+                        })
+                    }
+                    GP_EVENT_CAPTURE_COMPLETE => TetherEvent::CaptureComplete,
+                    GP_EVENT_TIMEOUT => TetherEvent::Timeout,
+                    _ => TetherEvent::Other
+                };
+                // event data is malloc'ed by libgphoto2 and owned by the caller
+                if !event_data.is_null() {
+                    libc::free(event_data);
+                }
+                Ok(event)
+            }
+        }
+
+        fn download(&mut self, path: &CameraFilePath) -> Result<Vec<u8>, PhotoError> {
+            let folder = CString::new(path.folder.as_str()).unwrap();
+            let name = CString::new(path.name.as_str()).unwrap();
+            unsafe {
+                let mut file = ptr::null_mut();
+                check(gp_file_new(&mut file), "gp_file_new")?;
+                let result = check(gp_camera_file_get(self.camera, folder.as_ptr(), name.as_ptr(), GP_FILE_TYPE_NORMAL, file, self.context), "downloading photo")
+                    .and_then(|_| {
+                        let mut data = ptr::null();
+                        let mut size = 0;
+                        check(gp_file_get_data_and_size(file, &mut data, &mut size), "reading photo data")?;
+                        Ok(std::slice::from_raw_parts(data as *const u8, size as usize).to_vec())
+                    });
+                gp_file_unref(file);
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Plays back a scripted sequence of events, then times out
+    struct StandInDevice {
+        events: VecDeque<TetherEvent>,
+        config: Vec<(String, String)>,
+        triggered: bool,
+    }
+
+    impl StandInDevice {
+        fn new(events: Vec<TetherEvent>) -> StandInDevice {
+            StandInDevice { events: events.into_iter().collect(), config: vec![], triggered: false }
+        }
+    }
+
+    impl TetheredDevice for StandInDevice {
+        fn set_config(&mut self, name: &str, value: &str) -> Result<(), PhotoError> {
+            assert!(!self.triggered, "settings should be applied before the shutter fires");
+            self.config.push((name.to_string(), value.to_string()));
+            Ok(())
+        }
+
+        fn trigger_capture(&mut self) -> Result<(), PhotoError> {
+            self.triggered = true;
+            Ok(())
+        }
+
+        fn wait_for_event(&mut self, timeout: Duration) -> Result<TetherEvent, PhotoError> {
+            assert!(self.triggered, "waiting for events before the shutter fired");
+            match self.events.pop_front() {
+                Some(event) => Ok(event),
+                None => {
+                    std::thread::sleep(timeout);
+                    Ok(TetherEvent::Timeout)
+                }
+            }
+        }
+
+        fn download(&mut self, path: &CameraFilePath) -> Result<Vec<u8>, PhotoError> {
+            Ok(format!("{}/{}", path.folder, path.name).into_bytes())
+        }
+    }
+
+    fn file(name: &str) -> TetherEvent {
+        TetherEvent::FileAdded(CameraFilePath { folder: String::from("/store/DCIM"), name: name.to_string() })
+    }
+
+    #[test]
+    fn downloads_the_added_file_after_applying_settings() {
+        let mut device = StandInDevice::new(vec![TetherEvent::Other, file("DSC001.JPG"), TetherEvent::CaptureComplete]);
+        let settings = CameraSettings { iso: Some(String::from("200")), shutter_speed: Some(String::from("1/30")), ..CameraSettings::default() };
+        let data = capture(&mut device, &settings, Duration::from_secs(5)).unwrap();
+        assert_eq!(data, b"/store/DCIM/DSC001.JPG".to_vec());
+        assert_eq!(device.config, vec![(String::from("iso"), String::from("200")), (String::from("shutterspeed"), String::from("1/30"))]);
+    }
+
+    #[test]
+    fn keeps_the_last_file_when_several_are_added() {
+        // RAW+JPEG
+        let mut device = StandInDevice::new(vec![file("DSC001.ARW"), file("DSC001.JPG"), TetherEvent::CaptureComplete]);
+        let data = capture(&mut device, &CameraSettings::default(), Duration::from_secs(5)).unwrap();
+        assert_eq!(data, b"/store/DCIM/DSC001.JPG".to_vec());
+    }
+
+    #[test]
+    fn prefers_the_jpeg_when_the_raw_file_arrives_last() {
+        let mut device = StandInDevice::new(vec![file("DSC001.JPG"), file("DSC001.ARW"), TetherEvent::CaptureComplete]);
+        let data = capture(&mut device, &CameraSettings::default(), Duration::from_secs(5)).unwrap();
+        assert_eq!(data, b"/store/DCIM/DSC001.JPG".to_vec());
+    }
+
+    #[test]
+    fn waits_past_a_raw_file_for_the_jpeg() {
+        let mut device = StandInDevice::new(vec![file("DSC001.ARW"), TetherEvent::Timeout, file("DSC001.JPG")]);
+        let data = capture(&mut device, &CameraSettings::default(), Duration::from_secs(5)).unwrap();
+        assert_eq!(data, b"/store/DCIM/DSC001.JPG".to_vec());
+    }
+
+    #[test]
+    fn returns_once_a_file_arrives_without_capture_complete() {
+        let mut device = StandInDevice::new(vec![file("DSC001.JPG")]);
+        let start = Instant::now();
+        let data = capture(&mut device, &CameraSettings::default(), Duration::from_secs(30)).unwrap();
+        assert_eq!(data, b"/store/DCIM/DSC001.JPG".to_vec());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn times_out_when_no_file_is_added() {
+        let mut device = StandInDevice::new(vec![TetherEvent::Timeout, TetherEvent::CaptureComplete]);
+        let result = capture(&mut device, &CameraSettings::default(), Duration::from_millis(200));
+        assert!(matches!(result, Err(PhotoError::Timeout)));
+    }
+
+    #[cfg(not(feature = "native-gphoto2"))]
+    #[test]
+    fn command_line_reports_downloads_oldest_first_once_written() {
+        let mut device = CommandLineDevice::new().unwrap();
+        std::fs::write(device.dir.path().join("capture-DSC001.ARW"), b"raw").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(device.dir.path().join("capture-DSC001.JPG"), b"jpeg").unwrap();
+
+        // sizes are noted on the first look, a file is only complete once they're unchanged
+        assert_eq!(device.next_download().unwrap(), None);
+        assert_eq!(device.next_download().unwrap().unwrap().name, "capture-DSC001.ARW");
+        assert_eq!(device.next_download().unwrap(), None);
+        assert_eq!(device.next_download().unwrap().unwrap().name, "capture-DSC001.JPG");
+        assert_eq!(device.next_download().unwrap(), None);
+    }
+}
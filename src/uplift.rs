@@ -0,0 +1,149 @@
+
+use opencv::prelude::*;
+use super::Resolution;
+use super::blend::{self, BlendCurve, Coverage};
+use super::photometric::{ChannelResponse, PointSamples, ProjectorResponse};
+use log::info;
+
+/// Black level measured at a projector's chessboard corners, with the projector and every
+/// other projector showing black, so overlaps read brighter
+pub struct BlackFloor {
+    /// camera RGB, row-major over the corners
+    grid: Vec<[f32; 3]>,
+    cols: usize,
+    rows: usize,
+}
+
+impl BlackFloor {
+    pub fn new(samples: &PointSamples, warp_res: Resolution) -> BlackFloor {
+        let measured: Vec<[f32; 3]> = samples.iter().filter_map(|s| *s).collect();
+        let mut fill = [0_f32; 3];
+        for rgb in measured.iter() {
+            for c in 0..3 { fill[c] += rgb[c] / measured.len().max(1) as f32; }
+        }
+        // corners that couldn't be sampled get the projector's average
+        BlackFloor {
+            grid: samples.iter().map(|s| s.unwrap_or(fill)).collect(),
+            cols: warp_res.width as usize,
+            rows: warp_res.height as usize,
+        }
+    }
+
+    fn max(&self) -> [f32; 3] {
+        let mut max = [0_f32; 3];
+        for rgb in self.grid.iter() {
+            for c in 0..3 { max[c] = max[c].max(rgb[c]); }
+        }
+        max
+    }
+
+    /// Black level at a normalized output position, held constant beyond the outer corners
+    fn at(&self, uv: glm::Vec2) -> [f32; 3] {
+        // corners are at (i + 1) / (cols + 1) across the output
+        let x = (uv.x * (self.cols + 1) as f32 - 1.).max(0.).min((self.cols - 1) as f32);
+        let y = (uv.y * (self.rows + 1) as f32 - 1.).max(0.).min((self.rows - 1) as f32);
+        let (i, j) = ((x as usize).min(self.cols - 2), (y as usize).min(self.rows - 2));
+        let (fx, fy) = (x - i as f32, y - j as f32);
+        let v = |i: usize, j: usize| self.grid[j * self.cols + i];
+        let mut rgb = [0_f32; 3];
+        for c in 0..3 {
+            let top = v(i, j)[c] * (1. - fx) + v(i + 1, j)[c] * fx;
+            let bottom = v(i, j + 1)[c] * (1. - fx) + v(i + 1, j + 1)[c] * fx;
+            rgb[c] = top * (1. - fy) + bottom * fy;
+        }
+        rgb
+    }
+}
+
+/// Projector level (0 to 1) that adds `light` camera units above black on one channel
+fn level_for(response: &ChannelResponse, light: f32) -> f32 {
+    let range = (response.white - response.black).max(1e-3);
+    (light / range).max(0.).min(1.).powf(1. / response.gamma.max(0.1))
+}
+
+/// Black level the dark parts of the surface are raised to: the brightest black anywhere,
+/// which is where the most projectors overlap
+pub fn uplift_target(floors: &[BlackFloor]) -> [f32; 3] {
+    let mut target = [0_f32; 3];
+    for floor in floors.iter() {
+        let max = floor.max();
+        for c in 0..3 { target[c] = target[c].max(max[c]); }
+    }
+    info!("raising black level to {:.1}, {:.1}, {:.1} (camera RGB)", target[0], target[1], target[2]);
+    target
+}
+
+/// Projector RGB levels (0 to 1) that bring the black level at a normalized output position of
+/// projector `index` up to `target`, sharing out the missing light like the blend where
+/// projectors overlap
+fn uplift_at(coverages: &[Coverage], floors: &[BlackFloor], responses: &[&ProjectorResponse], index: usize, uv: glm::Vec2, target: [f32; 3]) -> [f32; 3] {
+    let response = responses[index];
+    let channels = [&response.red, &response.green, &response.blue];
+    let floor = floors[index].at(uv);
+    let share = blend::blend_weight(coverages, index, uv, BlendCurve::Linear);
+    let mut rgb = [0_f32; 3];
+    for c in 0..3 {
+        rgb[c] = level_for(channels[c], (target[c] - floor[c]).max(0.) * share);
+    }
+    rgb
+}
+
+/// Image for projector `index` to show underneath dark content (the renderer outputs the
+/// larger of this and the content) so the black level matches `target` everywhere
+pub fn uplift_image(coverages: &[Coverage], floors: &[BlackFloor], responses: &[&ProjectorResponse], index: usize, projector_res: Resolution, target: [f32; 3]) -> opencv::Result<Mat> {
+    blend::render_map(projector_res, 3, |uv| {
+        let rgb = uplift_at(coverages, floors, responses, index, uv, target);
+        [rgb[2], rgb[1], rgb[0]] // BGR
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glm::{vec2, vec3};
+    use crate::surfaces::SurfaceType;
+
+    fn response() -> ProjectorResponse {
+        // linear, 100 camera units from black to white
+        let channel = ChannelResponse { gamma: 1., response: vec![0., 1.], black: 10., white: 110. };
+        ProjectorResponse { levels: vec![0, 255], red: channel.clone(), green: channel.clone(), blue: channel, black_samples: vec![], colour_samples: None }
+    }
+
+    #[test]
+    fn raises_single_coverage_to_the_overlap_black() {
+        // 5x3 corners one unit apart on a wall, the second projector 3.5 units to the right.
+        // Frames reach a unit past the outer corners, so they overlap from x = 2.5 to 5.
+        let warp_res = Resolution { width: 5, height: 3 };
+        let corners = |offset: f32| -> Vec<glm::Vec3> {
+            (0..15).map(|k| vec3(offset + (k % 5) as f32, (k / 5) as f32, 0.)).collect()
+        };
+        let (left, right) = (corners(0.), corners(3.5));
+        let coverages = [Coverage::new(&SurfaceType::Wall, &left, warp_res), Coverage::new(&SurfaceType::Wall, &right, warp_res)];
+        // both projectors' black adds up in the overlap
+        let black = |overlapped: bool| Some(if overlapped { [20., 20., 20.] } else { [10., 10., 10.] });
+        let floors = [
+            BlackFloor::new(&left.iter().map(|p| black(p.x > 2.5)).collect(), warp_res),
+            BlackFloor::new(&right.iter().map(|p| black(p.x < 5.)).collect(), warp_res),
+        ];
+        let target = uplift_target(&floors);
+        assert_eq!(target, [20., 20., 20.]);
+        let (left_response, right_response) = (response(), response());
+        let responses = [&left_response, &right_response];
+
+        // corners are at (i + 1) / 6 across and (j + 1) / 4 down the output
+        let at_corner = |i: usize| vec2((i + 1) as f32 / 6., 0.5);
+        // the left projector's own corners at x = 0 and 1 are raised by 10 of its 100 units
+        for &i in [0, 1].iter() {
+            let rgb = uplift_at(&coverages, &floors, &responses, 0, at_corner(i), target);
+            assert!(rgb.iter().all(|v| (v - 0.1).abs() < 1e-4), "{:?} at corner {}", rgb, i);
+        }
+        // and its corners at x = 3 and 4, inside the overlap, are left alone
+        for &i in [3, 4].iter() {
+            let rgb = uplift_at(&coverages, &floors, &responses, 0, at_corner(i), target);
+            assert!(rgb.iter().all(|v| v.abs() < 1e-6), "{:?} at corner {}", rgb, i);
+        }
+        // as are the right projector's corners in the overlap, while its own are raised
+        assert!(uplift_at(&coverages, &floors, &responses, 1, at_corner(0), target).iter().all(|v| v.abs() < 1e-6));
+        assert!(uplift_at(&coverages, &floors, &responses, 1, at_corner(4), target).iter().all(|v| (v - 0.1).abs() < 1e-4));
+    }
+}
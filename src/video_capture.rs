@@ -0,0 +1,181 @@
+
+use opencv::prelude::*;
+use opencv::core::*;
+use opencv::videoio;
+use super::photo::{Camera, CameraMetadata, PhotoError};
+use super::Resolution;
+use log::{info, debug};
+
+/// Webcam, capture card or video file read through OpenCV's VideoCapture.
+///
+/// The `--camera` argument is `video:<device>[?options]` where device is a device index
+/// (`video:0`), a device path (`video:/dev/video2`) or a video file (`video:session.mp4`).
+/// Options are `size=1920x1080` to request a capture resolution, `warmup=5` to discard that
+/// many buffered frames before each photo and `average=8` to average that many frames into
+/// one photo to reduce noise.
+pub struct VideoCaptureCamera {
+    capture: videoio::VideoCapture,
+    source: String,
+    warmup_frames: u32,
+    average_frames: u32,
+    /// video files are recordings rather than a live view of the projection
+    live: bool,
+}
+
+impl VideoCaptureCamera {
+    pub fn open(spec: &str) -> Result<VideoCaptureCamera, PhotoError> {
+        let spec = spec.strip_prefix("video:").unwrap_or(spec);
+        let (source, query) = match spec.find('?') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => (spec, "")
+        };
+
+        let mut size = None;
+        let mut warmup_frames = 5;
+        let mut average_frames = 1;
+        for (key, value) in query.split('&').filter(|p| !p.is_empty()).map(|p| {
+            let mut kv = p.splitn(2, '=');
+            (kv.next().unwrap(), kv.next().unwrap_or(""))
+        }) {
+            let bad_value = || PhotoError::Camera(format!("invalid value '{}' for video option {}", value, key));
+            match key {
+                "size" => size = Some(Resolution::parse(value).map_err(|_| bad_value())?),
+                "warmup" => warmup_frames = value.parse().map_err(|_| bad_value())?,
+                "average" => average_frames = value.parse::<u32>().map_err(|_| bad_value())?.max(1),
+                _ => return Err(PhotoError::Camera(format!("unknown video option {}", key)))
+            }
+        }
+
+        let capture = match source.parse::<i32>() {
+            Ok(index) => videoio::VideoCapture::new(index, videoio::CAP_ANY),
+            Err(_) => videoio::VideoCapture::from_file(source, videoio::CAP_ANY)
+        }.map_err(|e| PhotoError::Camera(e.to_string()))?;
+        if !capture.is_opened().unwrap_or(false) {
+            return Err(PhotoError::NotFound(format!("failed to open video source {}", source)));
+        }
+
+        let mut camera = VideoCaptureCamera {
+            capture: capture,
+            source: source.to_string(),
+            warmup_frames: warmup_frames,
+            average_frames: average_frames,
+            live: !std::path::Path::new(source).is_file(),
+        };
+        if let Some(size) = size {
+            camera.request_size(size)?;
+        }
+        Ok(camera)
+    }
+
+    fn request_size(&mut self, size: Resolution) -> Result<(), PhotoError> {
+        let cv_err = |e: opencv::Error| PhotoError::Camera(e.to_string());
+        self.capture.set(videoio::CAP_PROP_FRAME_WIDTH, size.width as f64).map_err(cv_err)?;
+        self.capture.set(videoio::CAP_PROP_FRAME_HEIGHT, size.height as f64).map_err(cv_err)?;
+
+        // drivers pick the closest mode they support, so report what we actually got
+        let width = self.capture.get(videoio::CAP_PROP_FRAME_WIDTH).map_err(cv_err)?;
+        let height = self.capture.get(videoio::CAP_PROP_FRAME_HEIGHT).map_err(cv_err)?;
+        info!("requested {} video frames from {}, got {}x{}", size, self.source, width, height);
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Mat, PhotoError> {
+        let mut frame = Mat::default().map_err(|e| PhotoError::Camera(e.to_string()))?;
+        let ok = self.capture.read(&mut frame).map_err(|e| PhotoError::Camera(e.to_string()))?;
+        if !ok || frame.empty().unwrap_or(true) {
+            return Err(PhotoError::Camera(format!("no more frames from {}", self.source)));
+        }
+        Ok(frame)
+    }
+
+    fn average(frames: &[Mat]) -> opencv::Result<Mat> {
+        let mut sum = Mat::default()?;
+        for frame in frames.iter() {
+            let mut float_frame = Mat::default()?;
+            frame.convert_to(&mut float_frame, CV_32FC3, 1., 0.)?;
+            if sum.empty()? {
+                sum = float_frame;
+            } else {
+                let mut total = Mat::default()?;
+                add(&sum, &float_frame, &mut total, &Mat::default()?, -1)?;
+                sum = total;
+            }
+        }
+        let mut averaged = Mat::default()?;
+        sum.convert_to(&mut averaged, CV_8UC3, 1. / frames.len() as f64, 0.)?;
+        Ok(averaged)
+    }
+}
+
+impl Camera for VideoCaptureCamera {
+    fn capture(&mut self) -> Result<Mat, PhotoError> {
+        // devices buffer a few frames, throw them away so the photo shows what's projected now
+        for _ in 0..self.warmup_frames {
+            self.read_frame()?;
+        }
+
+        let mut frames = vec![];
+        for _ in 0..self.average_frames {
+            frames.push(self.read_frame()?);
+        }
+        debug!("averaging {} frames from {}", frames.len(), self.source);
+        if frames.len() == 1 {
+            return Ok(frames.pop().unwrap());
+        }
+        VideoCaptureCamera::average(&frames).map_err(|e| PhotoError::Camera(e.to_string()))
+    }
+
+    fn metadata(&self) -> CameraMetadata {
+        CameraMetadata { backend: String::from("video"), source: self.source.clone(), live: self.live }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a short MJPEG video whose frames are solid grey, frame `i` at level `i * 20`
+    fn write_fixture(dir: &std::path::Path, frames: i32) -> String {
+        let fname = dir.join("fixture.avi").display().to_string();
+        let fourcc = videoio::VideoWriter::fourcc('M' as i8, 'J' as i8, 'P' as i8, 'G' as i8).unwrap();
+        let mut writer = videoio::VideoWriter::new(&fname, fourcc, 10., Size::new(64, 48), true).unwrap();
+        for i in 0..frames {
+            let frame = Mat::new_rows_cols_with_default(48, 64, CV_8UC3, Scalar::all((i * 20) as f64)).unwrap();
+            writer.write(&frame).unwrap();
+        }
+        writer.release().unwrap();
+        fname
+    }
+
+    fn level(photo: &Mat) -> f64 {
+        mean(photo, &Mat::default().unwrap()).unwrap()[0]
+    }
+
+    #[test]
+    fn reads_frames_from_a_video_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = write_fixture(dir.path(), 4);
+        let mut camera = VideoCaptureCamera::open(&format!("video:{}?warmup=2", fname)).unwrap();
+        assert!(!camera.metadata().live);
+
+        let photo = camera.capture().unwrap();
+        assert_eq!((photo.cols(), photo.rows()), (64, 48));
+        assert!((level(&photo) - 40.).abs() < 4., "expected the third frame, got level {}", level(&photo));
+        // only one frame left, not enough for the warm-up
+        assert!(camera.capture().is_err());
+    }
+
+    #[test]
+    fn averages_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = write_fixture(dir.path(), 4);
+        let mut camera = VideoCaptureCamera::open(&format!("video:{}?warmup=0&average=4", fname)).unwrap();
+        let photo = camera.capture().unwrap();
+        assert!((level(&photo) - 30.).abs() < 4., "expected the average of all frames, got level {}", level(&photo));
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(VideoCaptureCamera::open("video:0?zoom=2").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+
+use super::projector::{ProjectorControl, ProjectorError, ProjectorStatus};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tungstenite::client::AutoStream;
+use tungstenite::stream::Stream;
+use tungstenite::{Message, WebSocket};
+use log::{info, warn, debug};
+
+/// Messages the renderer sends back
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply {
+    /// a frame is on screen
+    Displayed { frame: u64 },
+    Status {
+        #[serde(default)]
+        message: Option<String>,
+    },
+    Error { message: String },
+}
+
+/// Keeps a WebSocket open to the renderer, which is quicker than a POST per pattern for long
+/// pattern sequences and lets calibration updates be pushed live. Protocol:
+///
+/// - each pattern is a text message `{"type": "show_image", "frame": n, "format": "png"}`
+///   followed by a binary message with the encoded image
+/// - the renderer acknowledges with `{"type": "displayed", "frame": n}` once it's on screen
+/// - `{"type": "clear", "frame": n}` blanks the output, acknowledged like a pattern
+/// - `{"type": "set_calibration", "calibration": {...}}` applies a calibration
+/// - `{"type": "status"}` is answered with `{"type": "status", "message": ...}`
+pub struct WebSocketProjector {
+    socket: WebSocket<AutoStream>,
+    url: String,
+    /// last frame sent
+    frame: u64,
+    /// last frame the renderer acknowledged
+    displayed: Option<u64>,
+}
+
+fn ws_err(e: tungstenite::Error) -> ProjectorError {
+    match e {
+        tungstenite::Error::Io(ref io) if io.kind() == io::ErrorKind::WouldBlock || io.kind() == io::ErrorKind::TimedOut => ProjectorError::Timeout,
+        e => ProjectorError::Failed(format!("websocket error: {}", e))
+    }
+}
+
+impl WebSocketProjector {
+    pub fn connect(url: &str) -> Result<WebSocketProjector, ProjectorError> {
+        let (socket, _) = tungstenite::connect(url).map_err(|e| ProjectorError::Failed(format!("failed to connect to {}: {}", url, e)))?;
+        info!("connected to renderer at {}", url);
+        Ok(WebSocketProjector { socket: socket, url: url.to_string(), frame: 0, displayed: None })
+    }
+
+    fn tcp_stream(&self) -> &TcpStream {
+        match self.socket.get_ref() {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    fn send_json(&mut self, message: Value) -> Result<(), ProjectorError> {
+        self.socket.write_message(Message::Text(message.to_string())).map_err(ws_err)
+    }
+
+    /// Read the next reply from the renderer, giving up at the deadline
+    fn read_reply(&mut self, deadline: Instant) -> Result<Reply, ProjectorError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return Err(ProjectorError::Timeout);
+            }
+            self.tcp_stream().set_read_timeout(Some(remaining)).map_err(|e| ProjectorError::Failed(e.to_string()))?;
+            match self.socket.read_message().map_err(ws_err)? {
+                Message::Text(text) => match serde_json::from_str::<Reply>(&text) {
+                    Ok(Reply::Displayed { frame }) => {
+                        self.displayed = Some(frame);
+                        return Ok(Reply::Displayed { frame: frame });
+                    }
+                    Ok(Reply::Error { message }) => return Err(ProjectorError::Failed(format!("renderer reported: {}", message))),
+                    Ok(reply) => return Ok(reply),
+                    Err(e) => warn!("ignoring unexpected message from {}: {} ({})", self.url, text, e)
+                },
+                Message::Close(_) => return Err(ProjectorError::Failed(format!("{} closed the connection", self.url))),
+                // pings are answered by tungstenite
+                _ => {}
+            }
+        }
+    }
+}
+
+impl ProjectorControl for WebSocketProjector {
+    fn show_image(&mut self, image_bytes: &[u8], format: &str) -> Result<(), ProjectorError> {
+        self.frame += 1;
+        self.send_json(json!({ "type": "show_image", "frame": self.frame, "format": format }))?;
+        self.socket.write_message(Message::Binary(image_bytes.to_vec())).map_err(ws_err)
+    }
+
+    fn clear(&mut self) -> Result<(), ProjectorError> {
+        self.frame += 1;
+        self.send_json(json!({ "type": "clear", "frame": self.frame }))
+    }
+
+    fn push_calibration(&mut self, json: &str) -> Result<(), ProjectorError> {
+        let calibration: Value = serde_json::from_str(json).map_err(|e| ProjectorError::Failed(format!("invalid calibration JSON: {}", e)))?;
+        self.send_json(json!({ "type": "set_calibration", "calibration": calibration }))
+    }
+
+    fn status(&mut self) -> Result<ProjectorStatus, ProjectorError> {
+        self.send_json(json!({ "type": "status" }))?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            // acknowledgements may still be queued ahead of the reply
+            if let Reply::Status { message } = self.read_reply(deadline)? {
+                return Ok(ProjectorStatus { online: true, displayed_frame: self.displayed, message: message });
+            }
+        }
+    }
+
+    fn wait_until_displayed(&mut self, timeout: Duration) -> Result<(), ProjectorError> {
+        let deadline = Instant::now() + timeout;
+        while self.frame > 0 && self.displayed.map(|frame| frame < self.frame).unwrap_or(true) {
+            self.read_reply(deadline)?;
+        }
+        debug!("renderer confirmed frame {}", self.frame);
+        Ok(())
+    }
+}
+
+impl Drop for WebSocketProjector {
+    fn drop(&mut self) {
+        self.socket.close(None).ok();
+        // flush the close frame
+        self.socket.write_pending().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Something the renderer was sent: a text message, or the image that followed `show_image`
+    #[derive(Debug, PartialEq)]
+    enum Received {
+        Json(Value),
+        Image(Vec<u8>),
+    }
+
+    /// Renderer on a background thread that acknowledges frames if `acknowledge` is set and
+    /// answers status requests. Returns its URL and everything it's sent.
+    fn serve(acknowledge: bool) -> (String, Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            while let Ok(message) = socket.read_message() {
+                let reply = match message {
+                    Message::Text(text) => {
+                        let request: Value = serde_json::from_str(&text).unwrap();
+                        sender.send(Received::Json(request.clone())).ok();
+                        match request["type"].as_str().unwrap() {
+                            "clear" if acknowledge => Some(json!({ "type": "displayed", "frame": request["frame"] })),
+                            "show_image" => match socket.read_message() {
+                                Ok(Message::Binary(image)) => {
+                                    sender.send(Received::Image(image)).ok();
+                                    if acknowledge { Some(json!({ "type": "displayed", "frame": request["frame"] })) } else { None }
+                                }
+                                _ => Some(json!({ "type": "error", "message": "expected an image" }))
+                            },
+                            "status" => Some(json!({ "type": "status", "message": "test renderer" })),
+                            "set_calibration" => None,
+                            _ => Some(json!({ "type": "error", "message": "unknown request" }))
+                        }
+                    }
+                    Message::Close(_) => break,
+                    _ => None
+                };
+                if let Some(reply) = reply {
+                    socket.write_message(Message::Text(reply.to_string())).unwrap();
+                }
+            }
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn shows_patterns_and_waits_for_acknowledgement() {
+        let (url, received) = serve(true);
+        let mut projector = WebSocketProjector::connect(&url).unwrap();
+        projector.show_image(b"first", "png").unwrap();
+        projector.show_image(b"second", "jpg").unwrap();
+        projector.wait_until_displayed(Duration::from_secs(5)).unwrap();
+        projector.clear().unwrap();
+        projector.wait_until_displayed(Duration::from_secs(5)).unwrap();
+        projector.push_calibration(r#"{"fov": 45}"#).unwrap();
+
+        let status = projector.status().unwrap();
+        assert_eq!(status.displayed_frame, Some(3));
+        assert_eq!(status.message.as_deref(), Some("test renderer"));
+
+        let expected = vec![
+            Received::Json(json!({ "type": "show_image", "frame": 1, "format": "png" })),
+            Received::Image(b"first".to_vec()),
+            Received::Json(json!({ "type": "show_image", "frame": 2, "format": "jpg" })),
+            Received::Image(b"second".to_vec()),
+            Received::Json(json!({ "type": "clear", "frame": 3 })),
+            Received::Json(json!({ "type": "set_calibration", "calibration": { "fov": 45 } })),
+            Received::Json(json!({ "type": "status" })),
+        ];
+        let messages: Vec<Received> = received.iter().take(expected.len()).collect();
+        assert_eq!(messages, expected);
+    }
+
+    #[test]
+    fn times_out_without_acknowledgement() {
+        let (url, _received) = serve(false);
+        let mut projector = WebSocketProjector::connect(&url).unwrap();
+        projector.show_image(b"pattern", "png").unwrap();
+        match projector.wait_until_displayed(Duration::from_millis(100)) {
+            Err(ProjectorError::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reports_renderer_errors() {
+        let (url, _received) = serve(true);
+        let mut projector = WebSocketProjector::connect(&url).unwrap();
+        projector.send_json(json!({ "type": "reboot" })).unwrap();
+        match projector.status() {
+            Err(ProjectorError::Failed(message)) => assert!(message.contains("unknown request"), "{}", message),
+            other => panic!("expected the renderer's error, got {:?}", other)
+        }
+    }
+}